@@ -34,7 +34,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .max_width(200.0)
             .default_attribute(FontFamily::SYSTEM_UI)
             .alignment(TextAlignment::Start)
-            //.range_attribute(10..80, TextAttribute::FontSize(8.0))
+            .range_attribute(10..80, TextAttribute::FontSize(8.0))
             .range_attribute(20..120, FontFamily::SERIF)
             .range_attribute(40..60, FontWeight::BOLD)
             .range_attribute(60..140, FontWeight::THIN)
@@ -43,7 +43,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .range_attribute(160..190, TextAttribute::TextColor(Color::rgb(0., 0.6, 0.)))
             .range_attribute(200..240, TextAttribute::TextColor(Color::rgb(0., 0., 0.6)))
             .range_attribute(200.., FontWeight::EXTRA_BLACK)
-            //.range_attribute(220.., TextAttribute::FontSize(18.0))
+            .range_attribute(220.., TextAttribute::FontSize(18.0))
             .range_attribute(240.., FontStyle::Italic)
             .range_attribute(280.., TextAttribute::Underline(true))
             .range_attribute(320.., TextAttribute::Strikethrough(true))
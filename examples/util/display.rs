@@ -18,9 +18,8 @@
 
 //! Example harness for displaying a text layout.
 
-use cosmic_text::SwashCache;
-use piet::{kurbo::Vec2, TextLayout as _};
-use piet_cosmic_text::{LineProcessor, Text, TextLayout};
+use piet::{kurbo::Point, TextLayout as _};
+use piet_cosmic_text::{Text, TextLayout};
 use std::num::NonZeroU32;
 use winit::{
     dpi::LogicalSize,
@@ -75,7 +74,6 @@ pub(super) fn run(mut f: impl FnMut(&mut Text, usize, usize) -> TextLayout + 'st
 
     // Text resources.
     let mut text = Text::new();
-    let mut swash_cache = SwashCache::new();
 
     event_loop.run(move |event, _, control_flow| {
         control_flow.set_wait();
@@ -111,106 +109,26 @@ pub(super) fn run(mut f: impl FnMut(&mut Text, usize, usize) -> TextLayout + 'st
                 if text.is_loaded() {
                     // Calculate text layout.
                     let text_layout = f(&mut text, width, height);
-                    let mut lines = LineProcessor::new();
 
                     // Add an offset to the drawing.
                     let offset = {
                         let size = text_layout.size();
                         let x = ((width as f64 - size.width) / 2.0).max(0.0);
                         let y = ((height as f64 - size.height) / 2.0).max(0.0);
-                        Vec2::new(x, y)
+                        Point::new(x, y)
                     };
 
-                    let mut pixmap = tiny_skia::PixmapMut::from_bytes(
-                        bytemuck::cast_slice_mut(&mut buffer),
-                        width as u32,
-                        height as u32,
-                    )
-                    .unwrap();
-
-                    // Draw pixels.
-                    text.with_font_system_mut(|font_system| {
-                        text_layout.buffer().draw(
-                            font_system,
-                            &mut swash_cache,
-                            cosmic_text::Color::rgba(0, 0, 0, 0xFF),
-                            |x, y, w, h, color| {
-                                if x < 0 || y < 0 {
-                                    return;
-                                }
-
-                                pixmap.fill_rect(
-                                    tiny_skia::Rect::from_xywh(
-                                        x as f32 + offset.x as f32,
-                                        y as f32 + offset.y as f32,
-                                        w as f32,
-                                        h as f32,
-                                    )
-                                    .unwrap(),
-                                    &tiny_skia::Paint {
-                                        shader: tiny_skia::Shader::SolidColor({
-                                            let [r, g, b, a] =
-                                                [color.r(), color.g(), color.b(), color.a()];
-
-                                            tiny_skia::Color::from_rgba8(r, g, b, a)
-                                        }),
-                                        ..Default::default()
-                                    },
-                                    tiny_skia::Transform::identity(),
-                                    None,
-                                );
-                            },
-                        );
-                    });
-
-                    // Draw lines.
+                    // Draw the text.
                     text_layout
-                        .layout_runs()
-                        .flat_map(|run| {
-                            let line_y = run.line_y;
-                            run.glyphs.iter().map(move |glyph| (glyph, line_y))
-                        })
-                        .for_each(|(glyph, line_y)| {
-                            lines.handle_glyph(
-                                glyph,
-                                line_y,
-                                cosmic_text::Color::rgba(0, 0, 0, 0xFF),
-                            );
-                        });
-
-                    lines.lines().into_iter().for_each(|line| {
-                        tracing::trace!("Got line: {:?}", line);
-
-                        let rect = {
-                            let mut rect = line.into_rect();
-                            rect.x0 += offset.x;
-                            rect.y0 += offset.y;
-                            rect.x1 += offset.x;
-                            rect.y1 += offset.y;
-
-                            tiny_skia::Rect::from_ltrb(
-                                rect.x0 as f32,
-                                rect.y0 as f32,
-                                rect.x1 as f32,
-                                rect.y1 as f32,
-                            )
-                            .unwrap()
-                        };
-                        let color = {
-                            let (r, g, b, a) = line.color.as_rgba8();
-                            tiny_skia::Color::from_rgba8(r, g, b, a)
-                        };
-
-                        pixmap.fill_rect(
-                            rect,
-                            &tiny_skia::Paint {
-                                shader: tiny_skia::Shader::SolidColor(color),
-                                ..Default::default()
-                            },
-                            tiny_skia::Transform::identity(),
-                            None,
-                        );
-                    });
+                        .draw_into(
+                            bytemuck::cast_slice_mut(&mut buffer),
+                            width,
+                            height,
+                            width * 4,
+                            offset,
+                            piet::Color::BLACK,
+                        )
+                        .unwrap();
                 } else {
                     // Wait a second then try again.
                     control_flow.set_wait_timeout(std::time::Duration::from_secs(1));
@@ -0,0 +1,634 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+//! A texture atlas for drawing glyphs with the GPU.
+//!
+//! Glyphs are packed into shelves: rows of glyphs that share the same height. When every page is
+//! full, the shelves that were used the longest time ago are cleared to make room.
+
+use crate::raster::{texels, Rasterizer, Synthesis};
+use crate::text_layout::TextLayout;
+use crate::{cvt_color, Antialiasing, FontError, RenderOptions};
+
+use cosmic_text as ct;
+use ct::CacheKey;
+
+use piet::kurbo::{Point, Rect};
+
+use swash::scale::image::{Content, Image as SwashImage};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// The number of empty pixels left between glyphs, so that sampling one doesn't bleed into the
+/// next.
+const PADDING: u32 = 1;
+
+/// A texture atlas of rasterized glyphs.
+///
+/// The atlas is made up of square pages of premultiplied RGBA pixels. It is meant to be mirrored
+/// into GPU textures: call [`prepare`] for every layout that is drawn in a frame, upload the
+/// areas returned by [`take_dirty_rects`], draw the returned quads and then call [`next_frame`].
+///
+/// Glyphs that were used in the current frame are never evicted. Once every page is full, glyphs
+/// that haven't been used in a while are evicted to make room for new ones.
+///
+/// With subpixel antialiasing, the pixels of ordinary glyphs hold the coverage of each channel
+/// instead of a color; see [`GlyphQuad::subpixel`]. The atlas is cleared when the antialiasing
+/// mode or the [`RenderOptions`] of the [`Text`] change.
+///
+/// [`prepare`]: GlyphAtlas::prepare
+/// [`take_dirty_rects`]: GlyphAtlas::take_dirty_rects
+/// [`next_frame`]: GlyphAtlas::next_frame
+/// [`Text`]: crate::Text
+/// [`RenderOptions`]: crate::RenderOptions
+pub struct GlyphAtlas {
+    /// The width and height of each page, in pixels.
+    page_size: u32,
+
+    /// The maximum number of pages to allocate.
+    max_pages: usize,
+
+    /// The pages of the atlas.
+    pages: Vec<Page>,
+
+    /// The glyphs in the atlas.
+    entries: HashMap<(CacheKey, Synthesis), Entry>,
+
+    /// The number of entries for each glyph, whatever styles were synthesized for it.
+    glyphs: HashMap<CacheKey, usize>,
+
+    /// The current frame.
+    frame: u64,
+
+    /// How the glyphs in the atlas are antialiased.
+    antialiasing: Antialiasing,
+
+    /// The options the glyphs in the atlas were rendered with.
+    options: RenderOptions,
+
+    /// The areas that have changed since the last call to `take_dirty_rects`.
+    dirty: Vec<DirtyRect>,
+}
+
+/// A page of the atlas.
+struct Page {
+    /// The pixel data, as premultiplied RGBA.
+    pixels: Vec<u8>,
+
+    /// The shelves of glyphs on this page, from top to bottom.
+    shelves: Vec<Shelf>,
+
+    /// Whether the entire page needs to be uploaded.
+    fully_dirty: bool,
+}
+
+/// A row of glyphs.
+struct Shelf {
+    /// The top of the shelf.
+    y: u32,
+
+    /// The height of the shelf, including padding.
+    height: u32,
+
+    /// The left edge of the free space on the shelf.
+    next_x: u32,
+
+    /// The last frame that a glyph on this shelf was used in.
+    last_used: u64,
+
+    /// The glyphs on this shelf.
+    keys: Vec<(CacheKey, Synthesis)>,
+}
+
+/// A glyph in the atlas.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// The page the glyph is on.
+    page: usize,
+
+    /// The position of the glyph on the page.
+    x: u32,
+    y: u32,
+
+    /// The size of the glyph, in pixels.
+    width: u32,
+    height: u32,
+
+    /// The offset from the glyph's origin to its top left corner.
+    left: i32,
+    top: i32,
+
+    /// Whether the glyph has colors of its own.
+    is_color: bool,
+
+    /// Whether the glyph is a subpixel mask.
+    is_subpixel: bool,
+}
+
+/// An area of a page that has changed and needs to be uploaded.
+///
+/// This is returned by [`GlyphAtlas::take_dirty_rects`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    /// The index of the page.
+    pub page: usize,
+
+    /// The left edge of the area, in pixels.
+    pub x: u32,
+
+    /// The top edge of the area, in pixels.
+    pub y: u32,
+
+    /// The width of the area, in pixels.
+    pub width: u32,
+
+    /// The height of the area, in pixels.
+    pub height: u32,
+}
+
+/// A glyph to draw from the atlas.
+///
+/// This is returned by [`GlyphAtlas::prepare`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlyphQuad {
+    /// The index of the page that the glyph is on.
+    pub page: usize,
+
+    /// The area of the page to sample, normalized so that the page covers `0.0` to `1.0`.
+    pub uv: Rect,
+
+    /// Where to draw the glyph, in the same coordinates as the origin passed to `prepare`.
+    pub rect: Rect,
+
+    /// The color to multiply the sampled pixels by.
+    ///
+    /// This is the color of the text for ordinary glyphs. Color glyphs are white, with the
+    /// opacity of the text.
+    pub color: piet::Color,
+
+    /// Whether the sampled pixels hold the coverage of each channel.
+    ///
+    /// This is the case for ordinary glyphs with subpixel antialiasing. The red, green and blue
+    /// channels are then multiplied by the alpha of `color` and used to blend each channel of
+    /// `color` separately, for instance with dual-source blending. The alpha channel holds the
+    /// largest of the three.
+    pub subpixel: bool,
+}
+
+impl Shelf {
+    fn new(y: u32, height: u32, last_used: u64) -> Self {
+        Self {
+            y,
+            height,
+            next_x: 0,
+            last_used,
+            keys: vec![],
+        }
+    }
+}
+
+impl fmt::Debug for GlyphAtlas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GlyphAtlas")
+            .field("page_size", &self.page_size)
+            .field("max_pages", &self.max_pages)
+            .field("pages", &self.pages.len())
+            .field("glyphs", &self.entries.len())
+            .field("frame", &self.frame)
+            .field("antialiasing", &self.antialiasing)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GlyphAtlas {
+    /// Create a new, empty atlas.
+    ///
+    /// Pages are `page_size` pixels wide and tall, and no more than `max_pages` of them are
+    /// allocated.
+    pub fn new(page_size: u32, max_pages: usize) -> Self {
+        Self {
+            page_size,
+            max_pages,
+            pages: vec![],
+            entries: HashMap::new(),
+            glyphs: HashMap::new(),
+            frame: 0,
+            antialiasing: Antialiasing::default(),
+            options: RenderOptions::default(),
+            dirty: vec![],
+        }
+    }
+
+    /// Get the width and height of each page, in pixels.
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// Get the number of pages that have been allocated.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Get the pixels of a page, as premultiplied RGBA.
+    pub fn page(&self, index: usize) -> Option<&[u8]> {
+        self.pages.get(index).map(|page| &page.pixels[..])
+    }
+
+    /// Get the number of glyphs in the atlas.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Tell whether the atlas contains no glyphs.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Tell whether a glyph is in the atlas.
+    pub fn contains(&self, key: &CacheKey) -> bool {
+        self.glyphs.contains_key(key)
+    }
+
+    /// Start a new frame.
+    ///
+    /// Glyphs that were used before this call may be evicted to make room for new ones.
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Remove every glyph from the atlas.
+    ///
+    /// Pages stay allocated, and are uploaded again.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.glyphs.clear();
+
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            page.pixels.fill(0);
+            page.shelves.clear();
+
+            if !page.fully_dirty {
+                page.fully_dirty = true;
+                self.dirty.push(DirtyRect {
+                    page: index,
+                    x: 0,
+                    y: 0,
+                    width: self.page_size,
+                    height: self.page_size,
+                });
+            }
+        }
+    }
+
+    /// Take the areas of the pages that have changed since the last call.
+    pub fn take_dirty_rects(&mut self) -> Vec<DirtyRect> {
+        for page in &mut self.pages {
+            page.fully_dirty = false;
+        }
+
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Add the glyphs of a layout to the atlas and get the quads to draw them with.
+    ///
+    /// `origin` is the position of the top left corner of the layout. Glyphs without any pixels,
+    /// like spaces, don't get a quad. Underlines and strikethroughs aren't included; use a
+    /// [`LineProcessor`] to draw them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the font system is already borrowed or hasn't loaded yet, or if a glyph
+    /// doesn't fit into the atlas.
+    ///
+    /// [`LineProcessor`]: crate::LineProcessor
+    pub fn prepare(
+        &mut self,
+        layout: &TextLayout,
+        origin: impl Into<Point>,
+        default_color: piet::Color,
+    ) -> Result<Vec<GlyphQuad>, piet::Error> {
+        let origin = origin.into();
+        let default_color = cvt_color(default_color);
+
+        let (antialiasing, options) = layout.render_options();
+        if antialiasing != self.antialiasing || options != self.options {
+            self.clear();
+            self.antialiasing = antialiasing;
+            self.options = options;
+        }
+
+        layout.with_rasterizer(|raster, system| {
+            let mut quads = vec![];
+
+            for run in layout.layout_runs() {
+                for glyph in run.glyphs {
+                    let physical =
+                        glyph.physical((origin.x as f32, origin.y as f32 + run.line_y), 1.0);
+                    let key = (
+                        physical.cache_key,
+                        raster.synthesis(glyph, layout.attrs_table(), system),
+                    );
+                    let entry = match self.get_or_insert(key, raster, system)? {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+
+                    let color = glyph.color_opt.unwrap_or(default_color);
+                    let color = if entry.is_color {
+                        piet::Color::WHITE.with_alpha(f64::from(color.a()) / 255.0)
+                    } else {
+                        piet::Color::rgba8(color.r(), color.g(), color.b(), color.a())
+                    };
+
+                    let x = f64::from(physical.x + entry.left);
+                    let y = f64::from(physical.y - entry.top);
+                    let (width, height) = (f64::from(entry.width), f64::from(entry.height));
+                    let page_size = f64::from(self.page_size);
+
+                    quads.push(GlyphQuad {
+                        page: entry.page,
+                        uv: Rect::new(
+                            f64::from(entry.x) / page_size,
+                            f64::from(entry.y) / page_size,
+                            (f64::from(entry.x) + width) / page_size,
+                            (f64::from(entry.y) + height) / page_size,
+                        ),
+                        rect: Rect::new(x, y, x + width, y + height),
+                        color,
+                        subpixel: entry.is_subpixel,
+                    });
+                }
+            }
+
+            Ok(quads)
+        })?
+    }
+
+    /// Get a glyph from the atlas, rasterizing and adding it if it isn't there yet.
+    ///
+    /// Returns `None` for glyphs without any pixels.
+    fn get_or_insert(
+        &mut self,
+        key: (CacheKey, Synthesis),
+        raster: &mut Rasterizer,
+        system: &mut ct::FontSystem,
+    ) -> Result<Option<Entry>, piet::Error> {
+        if let Some(&entry) = self.entries.get(&key) {
+            let frame = self.frame;
+            if let Some(shelf) = self.pages[entry.page]
+                .shelves
+                .iter_mut()
+                .find(|shelf| shelf.y == entry.y)
+            {
+                shelf.last_used = frame;
+            }
+            return Ok(Some(entry));
+        }
+
+        let image = match raster.image(key.0, key.1.clone(), system) {
+            Some(image) if image.placement.width > 0 && image.placement.height > 0 => image,
+            _ => return Ok(None),
+        };
+
+        let (width, height) = (image.placement.width, image.placement.height);
+        let (page, shelf, x) = self.allocate(width + PADDING, height + PADDING)?;
+
+        let entry = Entry {
+            page,
+            x,
+            y: self.pages[page].shelves[shelf].y,
+            width,
+            height,
+            left: image.placement.left,
+            top: image.placement.top,
+            is_color: matches!(image.content, Content::Color),
+            is_subpixel: matches!(image.content, Content::SubpixelMask),
+        };
+
+        self.write(&entry, image);
+
+        trace!(
+            "added glyph {} to atlas page {} at ({}, {})",
+            key.0.glyph_id,
+            page,
+            entry.x,
+            entry.y
+        );
+
+        self.pages[page].shelves[shelf].keys.push(key.clone());
+        *self.glyphs.entry(key.0).or_insert(0) += 1;
+        self.entries.insert(key, entry);
+
+        Ok(Some(entry))
+    }
+
+    /// Find space for a glyph, returning its page, shelf and horizontal position.
+    fn allocate(&mut self, width: u32, height: u32) -> Result<(usize, usize, u32), piet::Error> {
+        let frame = self.frame;
+        let page_size = self.page_size;
+
+        if width > page_size || height > page_size {
+            warn!(
+                "glyph of {}x{} pixels doesn't fit into an atlas page",
+                width, height
+            );
+            return Err(piet::Error::BackendError(FontError::AtlasFull.into()));
+        }
+
+        // Use the shelf that wastes the least height.
+        let best = self
+            .pages
+            .iter()
+            .enumerate()
+            .flat_map(|(page_index, page)| {
+                page.shelves
+                    .iter()
+                    .enumerate()
+                    .map(move |(shelf_index, shelf)| (page_index, shelf_index, shelf))
+            })
+            .filter(|(_, _, shelf)| shelf.height >= height && shelf.next_x + width <= page_size)
+            .min_by_key(|(_, _, shelf)| shelf.height);
+
+        if let Some((page, shelf, _)) = best {
+            return Ok(self.claim(page, shelf, width, frame));
+        }
+
+        // Start a new shelf below the existing ones.
+        let free_page = self.pages.iter().position(|page| {
+            let bottom = page
+                .shelves
+                .last()
+                .map_or(0, |shelf| shelf.y + shelf.height);
+            bottom + height <= page_size
+        });
+
+        let page = match free_page {
+            Some(page) => Some(page),
+            None if self.pages.len() < self.max_pages => {
+                let size = page_size as usize;
+                self.pages.push(Page {
+                    pixels: vec![0; size * size * 4],
+                    shelves: vec![],
+                    fully_dirty: true,
+                });
+                self.dirty.push(DirtyRect {
+                    page: self.pages.len() - 1,
+                    x: 0,
+                    y: 0,
+                    width: page_size,
+                    height: page_size,
+                });
+
+                Some(self.pages.len() - 1)
+            }
+            None => None,
+        };
+
+        if let Some(page) = page {
+            let shelves = &mut self.pages[page].shelves;
+            let y = shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+            shelves.push(Shelf::new(y, height, frame));
+
+            let shelf = shelves.len() - 1;
+            return Ok(self.claim(page, shelf, width, frame));
+        }
+
+        // Evict the least recently used run of neighbouring shelves that is tall enough, and
+        // merge it into one shelf for this glyph.
+        let mut victim: Option<(usize, Range<usize>, u64)> = None;
+        for (page_index, page) in self.pages.iter().enumerate() {
+            for start in 0..page.shelves.len() {
+                let mut total = 0;
+                let mut newest = 0;
+
+                for (end, shelf) in page.shelves.iter().enumerate().skip(start) {
+                    if shelf.last_used >= frame {
+                        break;
+                    }
+
+                    total += shelf.height;
+                    newest = newest.max(shelf.last_used);
+
+                    if total >= height {
+                        if victim.as_ref().map_or(true, |(_, _, best)| newest < *best) {
+                            victim = Some((page_index, start..end + 1, newest));
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        let (page, range, _) =
+            victim.ok_or(piet::Error::BackendError(FontError::AtlasFull.into()))?;
+        for shelf in range.clone() {
+            self.evict(page, shelf);
+        }
+
+        let shelves = &mut self.pages[page].shelves;
+        let y = shelves[range.start].y;
+        let total = shelves[range.clone()]
+            .iter()
+            .map(|shelf| shelf.height)
+            .sum::<u32>();
+
+        // Keep the rest of the space around for shorter glyphs.
+        let mut merged = vec![Shelf::new(y, height, frame)];
+        if total > height {
+            merged.push(Shelf::new(y + height, total - height, 0));
+        }
+        shelves.splice(range.clone(), merged);
+
+        Ok(self.claim(page, range.start, width, frame))
+    }
+
+    /// Take space on a shelf.
+    fn claim(&mut self, page: usize, shelf: usize, width: u32, frame: u64) -> (usize, usize, u32) {
+        let shelf_data = &mut self.pages[page].shelves[shelf];
+        let x = shelf_data.next_x;
+        shelf_data.next_x += width;
+        shelf_data.last_used = frame;
+
+        (page, shelf, x)
+    }
+
+    /// Remove every glyph from a shelf and clear its pixels.
+    fn evict(&mut self, page: usize, shelf: usize) {
+        let page_size = self.page_size;
+        let page_data = &mut self.pages[page];
+        let shelf_data = &mut page_data.shelves[shelf];
+
+        trace!(
+            "evicting {} glyphs from atlas page {}",
+            shelf_data.keys.len(),
+            page
+        );
+
+        for key in shelf_data.keys.drain(..) {
+            if let Some(count) = self.glyphs.get_mut(&key.0) {
+                *count -= 1;
+                if *count == 0 {
+                    self.glyphs.remove(&key.0);
+                }
+            }
+            self.entries.remove(&key);
+        }
+        shelf_data.next_x = 0;
+
+        let row_bytes = page_size as usize * 4;
+        let start = shelf_data.y as usize * row_bytes;
+        let end = start + shelf_data.height as usize * row_bytes;
+        page_data.pixels[start..end].fill(0);
+
+        if !page_data.fully_dirty {
+            self.dirty.push(DirtyRect {
+                page,
+                x: 0,
+                y: shelf_data.y,
+                width: page_size,
+                height: shelf_data.height,
+            });
+        }
+    }
+
+    /// Copy the pixels of a glyph into its place in the atlas.
+    fn write(&mut self, entry: &Entry, image: &SwashImage) {
+        let row_bytes = self.page_size as usize * 4;
+        let page = &mut self.pages[entry.page];
+
+        for (i, texel) in texels(image).enumerate() {
+            let x = entry.x as usize + i % entry.width as usize;
+            let y = entry.y as usize + i / entry.width as usize;
+            let start = y * row_bytes + x * 4;
+            page.pixels[start..start + 4].copy_from_slice(&texel);
+        }
+
+        if !page.fully_dirty {
+            self.dirty.push(DirtyRect {
+                page: entry.page,
+                x: entry.x,
+                y: entry.y,
+                width: entry.width,
+                height: entry.height,
+            });
+        }
+    }
+}
@@ -18,9 +18,12 @@
 
 //! Used to translate `piet` text attributes to `cosmic-text` text attributes.
 
-use crate::metadata::Metadata;
+use crate::metadata::{Metadata, STYLE_INDEX_LIMIT};
 use crate::text::FontSystemAndDefaults;
-use crate::{cvt_color, cvt_family, cvt_style, cvt_weight};
+use crate::{
+    cvt_color, cvt_family, cvt_style, cvt_weight, DecorationLine, DecorationStyle, FontError,
+    FontFeature, FontVariation, POINTS_PER_INCH,
+};
 
 use cosmic_text as ct;
 use ct::{Attrs, AttrsList, AttrsOwned};
@@ -37,7 +40,7 @@ use std::ops::Range;
 #[derive(Default)]
 pub(crate) struct Attributes {
     /// List of text attributes.
-    attributes: Vec<TextAttribute>,
+    attributes: Vec<Attribute>,
 
     /// The starts and ends of the range.
     ///
@@ -96,6 +99,163 @@ impl fmt::Debug for Attributes {
     }
 }
 
+/// An attribute applied to a range of text.
+#[derive(Debug)]
+pub(crate) enum Attribute {
+    /// One of `piet`'s text attributes.
+    Piet(TextAttribute),
+
+    /// OpenType features to apply when shaping.
+    Features(Vec<FontFeature>),
+
+    /// Values for the axes of variable fonts.
+    Variations(Vec<FontVariation>),
+
+    /// Extra space after every grapheme cluster, in points.
+    LetterSpacing(f64),
+
+    /// Extra space after every word separator, in points.
+    WordSpacing(f64),
+
+    /// The color to paint behind the text.
+    Background(piet::Color),
+
+    /// Whether to draw a line above the text.
+    Overline(bool),
+
+    /// The style of the decoration lines.
+    DecorationStyle(DecorationStyle),
+
+    /// The color of one kind of decoration line, or of all of them.
+    DecorationColor(Option<DecorationLine>, piet::Color),
+}
+
+impl From<TextAttribute> for Attribute {
+    fn from(attr: TextAttribute) -> Self {
+        Self::Piet(attr)
+    }
+}
+
+/// Text attributes that `cosmic-text` has no way of representing.
+///
+/// Each unique set of these is stored in an [`ExtendedAttrsTable`]. The index into that table is
+/// stored in the [`Metadata`] of the glyphs that use it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExtendedAttrs {
+    /// The font size, in pixels.
+    pub(crate) font_size: f32,
+
+    /// The OpenType features to apply when shaping, with at most one entry per tag.
+    pub(crate) features: Vec<FontFeature>,
+
+    /// The values of variation axes, with at most one entry per tag.
+    pub(crate) variations: Vec<FontVariation>,
+
+    /// Extra space after every grapheme cluster, in pixels.
+    pub(crate) letter_spacing: f32,
+
+    /// Extra space after every word separator, in pixels.
+    pub(crate) word_spacing: f32,
+
+    /// The color to paint behind the text.
+    pub(crate) background: Option<piet::Color>,
+
+    /// The colors of the underline, overline and strikethrough, if they aren't the text color.
+    pub(crate) decoration_colors: [Option<piet::Color>; 3],
+}
+
+impl ExtendedAttrs {
+    /// Get the color of a kind of decoration line, if it isn't the text color.
+    pub(crate) fn decoration_color(&self, line: DecorationLine) -> Option<piet::Color> {
+        self.decoration_colors[decoration_index(line)]
+    }
+}
+
+/// Get the index of a kind of decoration line in [`ExtendedAttrs::decoration_colors`].
+fn decoration_index(line: DecorationLine) -> usize {
+    match line {
+        DecorationLine::Underline => 0,
+        DecorationLine::Overline => 1,
+        DecorationLine::Strikethrough => 2,
+    }
+}
+
+/// The unique [`ExtendedAttrs`] used by a layout.
+#[derive(Debug)]
+pub(crate) struct ExtendedAttrsTable {
+    /// The list of unique attributes.
+    ///
+    /// The first entry is always the default attributes.
+    entries: Vec<ExtendedAttrs>,
+
+    /// The DPI used to convert font sizes from points to pixels.
+    dpi: f64,
+}
+
+impl ExtendedAttrsTable {
+    /// Create a new table with the provided default attributes.
+    pub(crate) fn new(defaults: ExtendedAttrs, dpi: f64) -> Self {
+        Self {
+            entries: vec![defaults],
+            dpi,
+        }
+    }
+
+    /// Get the default attributes.
+    pub(crate) fn defaults(&self) -> &ExtendedAttrs {
+        &self.entries[0]
+    }
+
+    /// Get the attributes referred to by a glyph's metadata.
+    pub(crate) fn get(&self, metadata: usize) -> &ExtendedAttrs {
+        let index = Metadata::from_raw(metadata).style_index();
+        self.entries.get(index).unwrap_or_else(|| self.defaults())
+    }
+
+    /// Tell whether any of the attributes use OpenType features.
+    pub(crate) fn has_features(&self) -> bool {
+        self.entries.iter().any(|entry| !entry.features.is_empty())
+    }
+
+    /// Tell whether any of the attributes add letter or word spacing.
+    pub(crate) fn has_spacing(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.letter_spacing != 0.0 || entry.word_spacing != 0.0)
+    }
+
+    /// Get the variation axis values for a glyph, from its metadata.
+    ///
+    /// The `wght` axis comes first, set to the requested font weight, so that it is overridden by
+    /// any explicit value.
+    pub(crate) fn variations(&self, metadata: usize) -> impl Iterator<Item = FontVariation> + '_ {
+        let weight = Metadata::from_raw(metadata).boldness().to_raw();
+        std::iter::once(FontVariation::new(*b"wght", f32::from(weight)))
+            .chain(self.get(metadata).variations.iter().copied())
+    }
+
+    /// Get the index of a set of attributes, inserting them if they aren't already present.
+    ///
+    /// Fails if the index wouldn't fit in the metadata of a glyph.
+    fn intern(&mut self, attrs: ExtendedAttrs) -> Result<usize, Error> {
+        match self.entries.iter().position(|entry| *entry == attrs) {
+            Some(index) => Ok(index),
+            None if self.entries.len() >= STYLE_INDEX_LIMIT => {
+                Err(Error::BackendError(FontError::TooManyStyles.into()))
+            }
+            None => {
+                self.entries.push(attrs);
+                Ok(self.entries.len() - 1)
+            }
+        }
+    }
+
+    /// Convert a font size in points to a font size in pixels.
+    pub(crate) fn font_size_to_pixels(&self, points: f64) -> f32 {
+        (points * self.dpi / POINTS_PER_INCH) as f32
+    }
+}
+
 /// The start or end of a text attribute range.
 #[derive(Debug)]
 enum RangeEnd {
@@ -115,10 +275,10 @@ impl Default for RangeEnd {
 
 impl Attributes {
     /// Add a text attribute to the range.
-    pub(crate) fn push(&mut self, range: Range<usize>, attr: TextAttribute) {
+    pub(crate) fn push(&mut self, range: Range<usize>, attr: impl Into<Attribute>) {
         // Push the attribute itself.
         let index = self.attributes.len();
-        self.attributes.push(attr);
+        self.attributes.push(attr.into());
 
         // Push the range.
         macro_rules! push_index {
@@ -132,10 +292,18 @@ impl Attributes {
         push_index!(end, End);
     }
 
+    /// Tell whether any range asks for OpenType features.
+    pub(crate) fn has_features(&self) -> bool {
+        self.attributes
+            .iter()
+            .any(|attr| matches!(attr, Attribute::Features(features) if !features.is_empty()))
+    }
+
     /// Collect text attributes into a list.
     fn collect_attributes<'a>(
         &'a self,
         system: &mut FontSystemAndDefaults,
+        table: &mut ExtendedAttrsTable,
         mut attrs: Attrs<'a>,
         indices: impl Iterator<Item = usize>,
     ) -> Result<AttrsOwned, Error> {
@@ -153,18 +321,61 @@ impl Attributes {
             }};
         }
 
+        let mut extended = table.defaults().clone();
+
         for index in indices {
-            let piet_attr = self.attributes.get(index).ok_or_else(|| {
-                Error::BackendError(crate::FontError::InvalidAttributeIndex.into())
-            })?;
+            let piet_attr = self
+                .attributes
+                .get(index)
+                .ok_or_else(|| Error::BackendError(FontError::InvalidAttributeIndex.into()))?;
+            let piet_attr = match piet_attr {
+                Attribute::Piet(piet_attr) => piet_attr,
+                Attribute::Features(features) => {
+                    merge_tagged(&mut extended.features, features, FontFeature::tag);
+                    continue;
+                }
+                Attribute::Variations(variations) => {
+                    merge_tagged(&mut extended.variations, variations, FontVariation::tag);
+                    continue;
+                }
+                Attribute::LetterSpacing(spacing) => {
+                    extended.letter_spacing = table.font_size_to_pixels(*spacing);
+                    continue;
+                }
+                Attribute::WordSpacing(spacing) => {
+                    extended.word_spacing = table.font_size_to_pixels(*spacing);
+                    continue;
+                }
+                Attribute::Background(color) => {
+                    extended.background = Some(*color);
+                    continue;
+                }
+                Attribute::Overline(overline) => {
+                    with_metadata!(|meta| meta.set_overline(*overline));
+                    continue;
+                }
+                Attribute::DecorationStyle(style) => {
+                    with_metadata!(|meta| meta.set_decoration_style(*style));
+                    continue;
+                }
+                Attribute::DecorationColor(Some(line), color) => {
+                    extended.decoration_colors[decoration_index(*line)] = Some(*color);
+                    continue;
+                }
+                Attribute::DecorationColor(None, color) => {
+                    extended.decoration_colors = [Some(*color); 3];
+                    continue;
+                }
+            };
+
             match piet_attr {
                 TextAttribute::FontFamily(family) => {
                     attrs.family = cvt_family(family);
                 }
-                TextAttribute::FontSize(_size) => {
-                    // TODO: cosmic-text does not support variable sized text yet.
-                    // https://github.com/pop-os/cosmic-text/issues/64
-                    error!("piet-cosmic-text does not support variable size fonts yet");
+                TextAttribute::FontSize(size) => {
+                    // cosmic-text only supports one font size per buffer, so the size is applied
+                    // when we lay out the text ourselves.
+                    extended.font_size = table.font_size_to_pixels(*size);
                 }
                 TextAttribute::Strikethrough(st) => {
                     with_metadata!(|meta| meta.set_strikethrough(*st));
@@ -174,6 +385,7 @@ impl Attributes {
                 }
                 TextAttribute::Style(style) => {
                     attrs.style = cvt_style(*style);
+                    with_metadata!(|meta| meta.set_italic(*style == piet::FontStyle::Italic));
                 }
                 TextAttribute::Weight(weight) => {
                     attrs.weight = cvt_weight(*weight);
@@ -189,6 +401,11 @@ impl Attributes {
             }
         }
 
+        if extended != *table.defaults() {
+            let style_index = table.intern(extended)?;
+            with_metadata!(|meta| meta.set_style_index(style_index));
+        }
+
         Ok(system.fix_attrs(attrs))
     }
 
@@ -196,6 +413,7 @@ impl Attributes {
     pub(crate) fn text_attributes<'a>(
         &'a self,
         system: &mut FontSystemAndDefaults,
+        table: &mut ExtendedAttrsTable,
         range: Range<usize>,
         defaults: Attrs<'a>,
     ) -> Result<AttrsList, Error> {
@@ -246,7 +464,7 @@ impl Attributes {
             let current_range = last_index..index;
             if !current_range.is_empty() {
                 let new_attrs =
-                    self.collect_attributes(system, defaults, attr_list.iter().copied())?;
+                    self.collect_attributes(system, table, defaults, attr_list.iter().copied())?;
                 trace!("adding span {:?}", current_range);
                 result.add_span(current_range, new_attrs.as_attrs());
             } else {
@@ -274,7 +492,8 @@ impl Attributes {
         // Emit the final span.
         let current_range = last_index..range.end;
         if !current_range.is_empty() {
-            let new_attrs = self.collect_attributes(system, defaults, attr_list.into_iter())?;
+            let new_attrs =
+                self.collect_attributes(system, table, defaults, attr_list.into_iter())?;
             trace!("adding final span {:?}", current_range);
             result.add_span(current_range, new_attrs.as_attrs());
         } else {
@@ -284,3 +503,16 @@ impl Attributes {
         Ok(result)
     }
 }
+
+/// Add tagged settings to a list, replacing any earlier settings with the same tag.
+fn merge_tagged<T: Copy>(list: &mut Vec<T>, settings: &[T], tag: impl Fn(&T) -> [u8; 4]) {
+    for setting in settings {
+        match list
+            .iter_mut()
+            .find(|existing| tag(existing) == tag(setting))
+        {
+            Some(existing) => *existing = *setting,
+            None => list.push(*setting),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Shaping text again with the OpenType features and font variations requested for it.
+//!
+//! `cosmic-text` shapes text with `rustybuzz`, but doesn't pass on any features, and always
+//! uses the default instance of variable fonts. Words that need either are shaped again here,
+//! one run of glyphs from the same font instance at a time.
+
+use crate::attributes::ExtendedAttrsTable;
+use crate::layout::copy_glyph;
+use crate::raster::Variations;
+
+use cosmic_text as ct;
+use ct::{ShapeGlyph, ShapeWord};
+
+use std::ops::Range;
+
+use ttf_parser::Tag;
+
+/// Shape a word again, applying the OpenType features and font variations requested for it.
+///
+/// Each run of glyphs that `cosmic-text` took from the same instance of the same font is shaped
+/// again with that instance. Returns `None` if the word doesn't ask for any features, and only
+/// uses the default instances of its fonts.
+pub(crate) fn reshape_word(
+    word: &ShapeWord,
+    text: &str,
+    attrs_list: &ct::AttrsList,
+    rtl: bool,
+    table: &ExtendedAttrsTable,
+    font_system: &mut ct::FontSystem,
+) -> Option<Vec<ShapeGlyph>> {
+    let start = word.glyphs.iter().map(|glyph| glyph.start).min()?;
+    let end = word.glyphs.iter().map(|glyph| glyph.end).max()?;
+    let has_features = table.has_features()
+        && text[start..end].char_indices().any(|(i, _)| {
+            !table
+                .get(attrs_list.get_span(start + i).metadata)
+                .features
+                .is_empty()
+        });
+
+    // Figure out which instance of its font each glyph uses.
+    let mut last: Option<(ct::fontdb::ID, usize, Variations)> = None;
+    let instances = word
+        .glyphs
+        .iter()
+        .map(|glyph| match &last {
+            Some((font_id, metadata, variations))
+                if *font_id == glyph.font_id && *metadata == glyph.metadata =>
+            {
+                variations.clone()
+            }
+            _ => {
+                let variations = font_system
+                    .get_font(glyph.font_id)
+                    .map(|font| Variations::new(font.as_swash(), table.variations(glyph.metadata)))
+                    .unwrap_or_default();
+                last = Some((glyph.font_id, glyph.metadata, variations.clone()));
+                variations
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if !has_features && instances.iter().all(Variations::is_default) {
+        return None;
+    }
+
+    let mut glyphs = Vec::with_capacity(word.glyphs.len());
+    let mut index = 0;
+
+    while let Some(first) = word.glyphs.get(index) {
+        let len = word.glyphs[index..]
+            .iter()
+            .zip(&instances[index..])
+            .position(|(glyph, instance)| {
+                glyph.font_id != first.font_id || *instance != instances[index]
+            })
+            .unwrap_or(word.glyphs.len() - index);
+        let run = &word.glyphs[index..index + len];
+        index += len;
+
+        let range = run.iter().map(|glyph| glyph.start).min().unwrap_or(0)
+            ..run.iter().map(|glyph| glyph.end).max().unwrap_or(0);
+
+        match shape_run(text, range, attrs_list, rtl, first, table, font_system) {
+            Some(shaped) => glyphs.extend(shaped),
+            None => glyphs.extend(run.iter().map(copy_glyph)),
+        }
+    }
+
+    Some(glyphs)
+}
+
+/// Shape a run of text using the font and font variations of its first glyph, and the features
+/// requested for the text.
+///
+/// Returns `None` if the font is missing or can't shape all of the text.
+fn shape_run(
+    text: &str,
+    range: Range<usize>,
+    attrs_list: &ct::AttrsList,
+    rtl: bool,
+    first: &ShapeGlyph,
+    table: &ExtendedAttrsTable,
+    font_system: &mut ct::FontSystem,
+) -> Option<Vec<ShapeGlyph>> {
+    let font_id = first.font_id;
+    let font = font_system.get_font(font_id)?;
+    let mut face = font.rustybuzz().clone();
+    for variation in table.variations(first.metadata) {
+        face.set_variation(Tag::from_bytes(&variation.tag()), variation.value());
+    }
+
+    let run = &text[range.clone()];
+
+    // Collect the features, split wherever the attributes change.
+    let mut features = vec![];
+    let mut chars = run.char_indices().peekable();
+    while let Some((start, _)) = chars.next() {
+        let metadata = attrs_list.get_span(range.start + start).metadata;
+        let mut end = run.len();
+        while let Some(&(next, _)) = chars.peek() {
+            if attrs_list.get_span(range.start + next).metadata != metadata {
+                end = next;
+                break;
+            }
+            chars.next();
+        }
+
+        features.extend(table.get(metadata).features.iter().map(|feature| {
+            rustybuzz::Feature::new(Tag::from_bytes(&feature.tag()), feature.value(), start..end)
+        }));
+    }
+
+    let mut buffer = rustybuzz::UnicodeBuffer::new();
+    buffer.set_direction(if rtl {
+        rustybuzz::Direction::RightToLeft
+    } else {
+        rustybuzz::Direction::LeftToRight
+    });
+    buffer.push_str(run);
+    buffer.guess_segment_properties();
+
+    let glyph_buffer = rustybuzz::shape(&face, &features, buffer);
+    let scale = face.units_per_em() as f32;
+    let ascent = f32::from(face.ascender()) / scale;
+    let descent = -f32::from(face.descender()) / scale;
+
+    let mut glyphs = glyph_buffer
+        .glyph_infos()
+        .iter()
+        .zip(glyph_buffer.glyph_positions())
+        .map(|(info, pos)| {
+            let start = range.start + info.cluster as usize;
+            let attrs = attrs_list.get_span(start);
+
+            Some(ShapeGlyph {
+                start,
+                end: range.end,
+                x_advance: pos.x_advance as f32 / scale,
+                y_advance: pos.y_advance as f32 / scale,
+                x_offset: pos.x_offset as f32 / scale,
+                y_offset: pos.y_offset as f32 / scale,
+                ascent,
+                descent,
+                font_id,
+                glyph_id: u16::try_from(info.glyph_id).ok().filter(|&id| id != 0)?,
+                color_opt: attrs.color_opt,
+                metadata: attrs.metadata,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    // Each cluster ends where the next one in logical order starts.
+    let mut next = (range.end, range.end);
+    let mut fix_end = |glyph: &mut ShapeGlyph| {
+        if glyph.start == next.0 {
+            glyph.end = next.1;
+        } else {
+            glyph.end = next.0;
+            next = (glyph.start, next.0);
+        }
+    };
+    if rtl {
+        glyphs.iter_mut().for_each(&mut fix_end);
+    } else {
+        glyphs.iter_mut().rev().for_each(&mut fix_end);
+    }
+
+    Some(glyphs)
+}
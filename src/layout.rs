@@ -0,0 +1,1072 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Lays out shaped text into lines.
+//!
+//! `cosmic-text` lays out an entire `Buffer` using a single font size. In order to support ranges
+//! of text with different font sizes, we take the lines that it has shaped and lay them out
+//! ourselves. Shaped glyphs are measured in ems, so they can be scaled to any size after the fact.
+
+use crate::attributes::ExtendedAttrsTable;
+use crate::features;
+use crate::text_layout_builder::TextOverflow;
+use crate::LineHeight;
+
+use cosmic_text as ct;
+use ct::{Buffer, LayoutGlyph, ShapeGlyph, ShapeLine};
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use unicode_bidi::Level;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Options for laying out text.
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    /// The height of each line.
+    pub(crate) line_height: LineHeight,
+
+    /// What to do with lines that are too long.
+    pub(crate) overflow: TextOverflow,
+
+    /// The shaping strategy used for the text.
+    pub(crate) shaping: ct::Shaping,
+
+    /// The maximum number of lines to lay out.
+    pub(crate) max_lines: Option<usize>,
+
+    /// The marker placed at the end of truncated lines.
+    pub(crate) ellipsis: String,
+}
+
+/// A line of text that has been laid out.
+pub(crate) struct Line {
+    /// The index of the `BufferLine` that this line was laid out from.
+    pub(crate) paragraph: usize,
+
+    /// Whether the paragraph this line belongs to is right-to-left.
+    pub(crate) rtl: bool,
+
+    /// The glyphs in this line, in visual order.
+    pub(crate) glyphs: Vec<LayoutGlyph>,
+
+    /// The clusters that the glyphs in this line make up, in visual order.
+    clusters: Vec<Cluster>,
+
+    /// The indices of `clusters`, sorted by the start of their text.
+    logical: Vec<usize>,
+
+    /// The range of text covered by this line, relative to the start of the paragraph.
+    pub(crate) range: Range<usize>,
+
+    /// The X coordinate of the left edge of the line, not including hanging whitespace.
+    pub(crate) left: f32,
+
+    /// The width of the line, not including whitespace hanging off of the end of it.
+    pub(crate) width: f32,
+
+    /// The width of the whitespace hanging off of the end of the line.
+    pub(crate) whitespace_width: f32,
+
+    /// The start of the whitespace hanging off of the end of the line, relative to the start of
+    /// the paragraph.
+    ///
+    /// This is equal to the end of `range` if there is no such whitespace.
+    pub(crate) whitespace_start: usize,
+
+    /// The Y coordinate of the top of the line.
+    pub(crate) top: f32,
+
+    /// The Y coordinate of the baseline of the line.
+    pub(crate) baseline: f32,
+
+    /// The height of the line.
+    pub(crate) height: f32,
+
+    /// The start of the text that was cut off of this line, relative to the start of the
+    /// paragraph.
+    ///
+    /// The text that was cut off extends to the end of `range`, or to the end of the text if
+    /// this is the last line.
+    pub(crate) truncated: Option<usize>,
+}
+
+impl Line {
+    /// Get the clusters of glyphs in this line, in visual order.
+    pub(crate) fn clusters(&self) -> &[Cluster] {
+        &self.clusters
+    }
+
+    /// Get the X coordinate of the caret at a text position.
+    ///
+    /// `text` is the text of the paragraph, and `index` is relative to the start of it.
+    pub(crate) fn caret_x(&self, text: &str, index: usize) -> f32 {
+        // Find the last cluster that starts at or before the index.
+        let after = self
+            .logical
+            .partition_point(|&i| self.clusters[i].range.start <= index);
+        let cluster = match after.checked_sub(1) {
+            Some(before) => &self.clusters[self.logical[before]],
+
+            // Otherwise, use the start of the line.
+            None => {
+                return self
+                    .logical
+                    .first()
+                    .map_or(self.left, |&i| self.clusters[i].leading_edge())
+            }
+        };
+
+        // Use the leading edge of the grapheme at the index, if it is in this line.
+        if cluster.range.contains(&index) {
+            cluster.grapheme_edge(text, index)
+        } else {
+            // Otherwise, use the trailing edge of the cluster before it.
+            cluster.trailing_edge()
+        }
+    }
+
+    /// Get the grapheme boundaries in this line, sorted by the X coordinate of their caret.
+    ///
+    /// Each boundary is placed where `caret_x` would put it, and is relative to the start of the
+    /// paragraph.
+    pub(crate) fn caret_stops(&self, text: &str) -> Vec<(f32, usize)> {
+        let mut stops = vec![];
+
+        for (n, &i) in self.logical.iter().enumerate() {
+            let cluster = &self.clusters[i];
+            if !cluster.range.is_empty() {
+                let boundaries = cluster.grapheme_boundaries(text);
+                let count = boundaries.len() - 1;
+                for (k, &boundary) in boundaries[..count].iter().enumerate() {
+                    stops.push((cluster.edge_at(k as f32 / count as f32), boundary));
+                }
+            }
+
+            // The end of a cluster is drawn at the start of the one after it, if there is one.
+            let next = self.logical.get(n + 1).map(|&j| &self.clusters[j]);
+            if next.map_or(true, |next| next.range.start > cluster.range.end) {
+                stops.push((cluster.trailing_edge(), cluster.range.end));
+            }
+        }
+
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        stops
+    }
+
+    /// Find the cluster containing an X coordinate, or the closest one to it.
+    ///
+    /// Also returns whether the coordinate is inside of the cluster.
+    pub(crate) fn cluster_at_x(&self, x: f32) -> Option<(&Cluster, bool)> {
+        // Clusters are in visual order, so they are sorted by their edges.
+        let index = self.clusters.partition_point(|c| c.x1 <= x);
+        if let Some(cluster) = self.clusters.get(index) {
+            if x >= cluster.x0 {
+                return Some((cluster, true));
+            }
+        }
+
+        // The point is between two clusters, or past either end of the line.
+        let distance = |c: &Cluster| (c.x0 - x).abs().min((c.x1 - x).abs());
+        let before = index.checked_sub(1).and_then(|i| self.clusters.get(i));
+        let after = self.clusters.get(index);
+        let closest = match (before, after) {
+            (Some(before), Some(after)) if distance(after) < distance(before) => after,
+            (Some(before), _) => before,
+            (None, after) => after?,
+        };
+
+        Some((closest, false))
+    }
+}
+
+/// A group of glyphs that were shaped from the same range of text.
+pub(crate) struct Cluster {
+    /// The range of text in the paragraph that this cluster covers.
+    pub(crate) range: Range<usize>,
+
+    /// The X coordinate of the left edge of the cluster.
+    pub(crate) x0: f32,
+
+    /// The X coordinate of the right edge of the cluster.
+    pub(crate) x1: f32,
+
+    /// Whether this cluster is right-to-left.
+    pub(crate) rtl: bool,
+}
+
+impl Cluster {
+    /// The edge of the cluster where its text starts.
+    pub(crate) fn leading_edge(&self) -> f32 {
+        self.edge_at(0.0)
+    }
+
+    /// The edge of the cluster where its text ends.
+    pub(crate) fn trailing_edge(&self) -> f32 {
+        self.edge_at(1.0)
+    }
+
+    /// Get the boundaries of the graphemes in this cluster, relative to the start of the paragraph.
+    ///
+    /// This includes both the start and the end of the cluster.
+    pub(crate) fn grapheme_boundaries(&self, text: &str) -> Vec<usize> {
+        let cluster_text = text.get(self.range.clone()).unwrap_or_default();
+
+        let mut boundaries = cluster_text
+            .grapheme_indices(true)
+            .map(|(start, _)| self.range.start + start)
+            .collect::<Vec<_>>();
+        if boundaries.is_empty() {
+            boundaries.push(self.range.start);
+        }
+        boundaries.push(self.range.end);
+
+        boundaries
+    }
+
+    /// Get the leading edge of the grapheme at an index inside of this cluster.
+    ///
+    /// Clusters like ligatures may contain several graphemes, in which case the cluster is
+    /// divided evenly between them.
+    pub(crate) fn grapheme_edge(&self, text: &str, index: usize) -> f32 {
+        let boundaries = self.grapheme_boundaries(text);
+        let count = boundaries.len() - 1;
+        let before = boundaries[..count]
+            .iter()
+            .rposition(|&boundary| boundary <= index)
+            .unwrap_or(0);
+
+        self.edge_at(before as f32 / count as f32)
+    }
+
+    /// Get the grapheme boundary closest to an X coordinate inside of this cluster.
+    pub(crate) fn hit(&self, text: &str, x: f32) -> usize {
+        let boundaries = self.grapheme_boundaries(text);
+        let count = boundaries.len() - 1;
+
+        // Figure out how far into the cluster the point is, in its reading direction.
+        let width = self.x1 - self.x0;
+        let fraction = if width > 0.0 {
+            let distance = if self.rtl { self.x1 - x } else { x - self.x0 };
+            (distance / width).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        // Round to the nearest boundary.
+        let nearest = (fraction * count as f32).round() as usize;
+        boundaries[nearest.min(count)]
+    }
+
+    /// Get the X coordinate of a fraction of the way through this cluster, in reading order.
+    fn edge_at(&self, fraction: f32) -> f32 {
+        let advance = (self.x1 - self.x0) * fraction;
+        if self.rtl {
+            self.x1 - advance
+        } else {
+            self.x0 + advance
+        }
+    }
+}
+
+/// A shaped glyph, in logical order.
+struct Item {
+    /// The shaped glyph.
+    glyph: ShapeGlyph,
+
+    /// The embedding level of the glyph.
+    level: Level,
+
+    /// The index of the word this glyph belongs to, counted across all spans.
+    word: usize,
+
+    /// Whether this glyph is whitespace.
+    blank: bool,
+
+    /// The font size of this glyph, in pixels.
+    font_size: f32,
+}
+
+impl Item {
+    /// The horizontal advance of this glyph, in pixels.
+    fn advance(&self) -> f32 {
+        self.glyph.x_advance * self.font_size
+    }
+}
+
+/// A paragraph that has been broken into lines, but not yet positioned.
+struct BrokenParagraph<'a> {
+    /// The index of the `BufferLine`.
+    index: usize,
+
+    /// The shaped line.
+    shape: &'a ShapeLine,
+
+    /// The length of the paragraph's text.
+    len: usize,
+
+    /// The lines that the paragraph was broken into.
+    lines: Vec<BrokenLine>,
+}
+
+/// The glyphs that make up one line.
+struct BrokenLine {
+    /// The glyphs in this line, in logical order.
+    items: Vec<Item>,
+
+    /// The index of the first item of whitespace hanging off the end of the line.
+    hang: usize,
+
+    /// The width of the line, not including hanging whitespace.
+    width: f32,
+
+    /// The text that was cut off of this line, if any.
+    truncated: Option<Range<usize>>,
+}
+
+/// Lay out the lines of a shaped buffer.
+pub(crate) fn layout(
+    buffer: &Buffer,
+    table: &ExtendedAttrsTable,
+    options: &Options,
+    font_system: &mut ct::FontSystem,
+) -> Vec<Line> {
+    let (max_width, _) = buffer.size();
+    let wrap = buffer.wrap();
+
+    // Break every paragraph into lines.
+    let mut paragraphs = buffer
+        .lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let shape = line.shape_opt().as_ref()?;
+            let items = logical_items(
+                shape,
+                line.text(),
+                line.attrs_list(),
+                options.shaping,
+                table,
+                font_system,
+            );
+            let mut lines = break_lines(items, max_width, wrap);
+
+            // Truncate lines that are still too long.
+            if options.overflow == TextOverflow::Ellipsis && max_width.is_finite() {
+                for broken in lines.iter_mut().filter(|broken| broken.width > max_width) {
+                    truncate_line(
+                        broken,
+                        line,
+                        shape.rtl,
+                        max_width,
+                        None,
+                        table,
+                        options,
+                        font_system,
+                    );
+                }
+            }
+
+            Some(BrokenParagraph {
+                index,
+                shape,
+                len: line.text().len(),
+                lines,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Drop any lines past the maximum number of lines.
+    if let Some(max_lines) = options.max_lines {
+        let max_lines = max_lines.max(1);
+        let total = paragraphs
+            .iter()
+            .map(|para| para.lines.len())
+            .sum::<usize>();
+
+        let mut count = 0;
+        let mut kept = 0;
+        for para in &mut paragraphs {
+            if count == max_lines {
+                break;
+            }
+
+            para.lines.truncate(max_lines - count);
+            count += para.lines.len();
+            kept += 1;
+        }
+        paragraphs.truncate(kept);
+
+        // Mark the end of the last line as cut off.
+        if let (true, Some(para)) = (total > max_lines, paragraphs.last_mut()) {
+            let paragraph = &buffer.lines[para.index];
+            let rtl = para.shape.rtl;
+            let len = para.len;
+
+            if let Some(last) = para.lines.last_mut() {
+                match options.overflow {
+                    TextOverflow::Ellipsis => truncate_line(
+                        last,
+                        paragraph,
+                        rtl,
+                        max_width,
+                        Some(len),
+                        table,
+                        options,
+                        font_system,
+                    ),
+                    TextOverflow::Visible => {
+                        let end = last.items.iter().map(|item| item.glyph.end).max();
+                        last.truncated = Some(end.unwrap_or(0)..len);
+                    }
+                }
+            }
+        }
+    }
+
+    // Without a width constraint, align against the widest line.
+    let align_width = if max_width.is_finite() {
+        max_width
+    } else {
+        paragraphs
+            .iter()
+            .flat_map(|para| para.lines.iter().map(|line| line.width))
+            .fold(0.0, f32::max)
+    };
+
+    let default_size = table.defaults().font_size;
+    let mut top = 0.0;
+    let mut lines = vec![];
+
+    for para in &paragraphs {
+        let rtl = para.shape.rtl;
+        let align = buffer.lines[para.index].align().unwrap_or(if rtl {
+            ct::Align::Right
+        } else {
+            ct::Align::Left
+        });
+
+        for (i, broken) in para.lines.iter().enumerate() {
+            let is_last = i + 1 == para.lines.len();
+            let (glyphs, left) = position_line(rtl, broken, align, align_width, is_last);
+
+            // Figure out the vertical metrics.
+            let (mut ascent, mut descent, mut font_size) = (0.0f32, 0.0f32, 0.0f32);
+            for item in &broken.items {
+                ascent = ascent.max(item.glyph.ascent * item.font_size);
+                descent = descent.max(item.glyph.descent * item.font_size);
+                font_size = font_size.max(item.font_size);
+            }
+            if broken.items.is_empty() {
+                font_size = default_size;
+            }
+
+            let height = match options.line_height {
+                LineHeight::Relative(multiple) => font_size * multiple as f32,
+                LineHeight::Absolute(points) => table.font_size_to_pixels(points),
+            };
+
+            let centering_offset = (height - (ascent + descent)) / 2.0;
+
+            // Figure out which part of the text this line covers.
+            let range = {
+                let start = if i == 0 {
+                    0
+                } else {
+                    broken
+                        .items
+                        .iter()
+                        .map(|item| item.glyph.start)
+                        .min()
+                        .unwrap_or(0)
+                };
+                // The line also covers the text cut off of it, which the marker may not.
+                let end = if is_last {
+                    para.len
+                } else {
+                    broken
+                        .items
+                        .iter()
+                        .map(|item| item.glyph.end)
+                        .chain(broken.truncated.as_ref().map(|hidden| hidden.end))
+                        .max()
+                        .unwrap_or(start)
+                };
+                start..end
+            };
+
+            // Figure out the whitespace hanging off of the end.
+            let hanging = &broken.items[broken.hang..];
+            let whitespace_width = hanging.iter().map(Item::advance).sum();
+            let whitespace_start = hanging
+                .iter()
+                .map(|item| item.glyph.start)
+                .min()
+                .map_or(range.end, |start| start.max(range.start));
+
+            let clusters = group_clusters(&glyphs);
+            let mut logical = (0..clusters.len()).collect::<Vec<_>>();
+            logical.sort_by_key(|&i| clusters[i].range.start);
+
+            lines.push(Line {
+                paragraph: para.index,
+                rtl,
+                glyphs,
+                clusters,
+                logical,
+                range,
+                left,
+                width: broken.width,
+                whitespace_width,
+                whitespace_start,
+                top,
+                baseline: top + centering_offset + ascent,
+                height,
+                truncated: broken.truncated.as_ref().map(|hidden| hidden.start),
+            });
+
+            top += height;
+        }
+    }
+
+    lines
+}
+
+/// Group the glyphs of a line, in visual order, into clusters.
+fn group_clusters(glyphs: &[LayoutGlyph]) -> Vec<Cluster> {
+    let mut clusters: Vec<Cluster> = vec![];
+
+    for glyph in glyphs {
+        // Glyphs from the same cluster are next to each other, even after reordering.
+        let range = glyph.start..glyph.end;
+        match clusters.last_mut() {
+            Some(cluster) if cluster.range == range => {
+                cluster.x0 = cluster.x0.min(glyph.x);
+                cluster.x1 = cluster.x1.max(glyph.x + glyph.w);
+            }
+            _ => clusters.push(Cluster {
+                range,
+                x0: glyph.x,
+                x1: glyph.x + glyph.w,
+                rtl: glyph.level.is_rtl(),
+            }),
+        }
+    }
+
+    clusters
+}
+
+/// Collect the glyphs of a shaped line in logical order.
+fn logical_items(
+    shape: &ShapeLine,
+    text: &str,
+    attrs_list: &ct::AttrsList,
+    shaping: ct::Shaping,
+    table: &ExtendedAttrsTable,
+    font_system: &mut ct::FontSystem,
+) -> Vec<Item> {
+    let mut items = vec![];
+    let mut word_index = 0;
+
+    for span in &shape.spans {
+        // `cosmic-text` stores words and glyphs in the visual order of the line's direction.
+        let logical = span.level.is_rtl() == shape.rtl;
+
+        // The basic shaper always emits glyphs in logical order, which are then reversed for
+        // right-to-left lines.
+        let logical_glyphs = match shaping {
+            ct::Shaping::Basic => !shape.rtl,
+            ct::Shaping::Advanced => logical,
+        };
+
+        let mut push_word = |word: &ct::ShapeWord| {
+            let reshaped = if shaping == ct::Shaping::Advanced {
+                features::reshape_word(
+                    word,
+                    text,
+                    attrs_list,
+                    span.level.is_rtl(),
+                    table,
+                    font_system,
+                )
+            } else {
+                None
+            };
+            let glyphs = reshaped.as_deref().unwrap_or(&word.glyphs);
+
+            let mut push_glyph = |glyph: &ShapeGlyph| {
+                items.push(Item {
+                    glyph: copy_glyph(glyph),
+                    level: span.level,
+                    word: word_index,
+                    blank: word.blank,
+                    font_size: table.get(glyph.metadata).font_size,
+                });
+            };
+
+            if logical_glyphs {
+                glyphs.iter().for_each(&mut push_glyph);
+            } else {
+                glyphs.iter().rev().for_each(&mut push_glyph);
+            }
+
+            word_index += 1;
+        };
+
+        if logical {
+            span.words.iter().for_each(&mut push_word);
+        } else {
+            span.words.iter().rev().for_each(&mut push_word);
+        }
+    }
+
+    if shaping == ct::Shaping::Basic {
+        repair_basic_items(&mut items, text, attrs_list, table, font_system);
+    }
+
+    if table.has_spacing() {
+        add_spacing(&mut items, text, table);
+    }
+
+    items
+}
+
+/// Add letter and word spacing to the advances of glyphs.
+///
+/// The spacing for a cluster is added to the last of its glyphs, so that it doesn't move any
+/// combining marks away from their base.
+fn add_spacing(items: &mut [Item], text: &str, table: &ExtendedAttrsTable) {
+    for i in 0..items.len() {
+        let start = items[i].glyph.start;
+        if items
+            .get(i + 1)
+            .map_or(false, |next| next.glyph.start == start)
+        {
+            continue;
+        }
+
+        let item = &mut items[i];
+        let attrs = table.get(item.glyph.metadata);
+        let cluster = text.get(start..item.glyph.end).unwrap_or_default();
+
+        // Ligatures get the spacing of every grapheme they cover.
+        let mut spacing = attrs.letter_spacing * cluster.graphemes(true).count().max(1) as f32;
+        if cluster.chars().any(is_word_separator) {
+            spacing += attrs.word_spacing;
+        }
+
+        if spacing != 0.0 && item.font_size > 0.0 {
+            item.glyph.x_advance += spacing / item.font_size;
+        }
+    }
+}
+
+/// Tell whether a character separates words, for the purposes of word spacing.
+///
+/// These are the word separators listed by CSS.
+fn is_word_separator(c: char) -> bool {
+    matches!(
+        c,
+        ' ' | '\u{a0}' | '\u{1361}' | '\u{10100}' | '\u{10101}' | '\u{1039f}' | '\u{1091f}'
+    )
+}
+
+/// Make glyphs from the basic shaper look like those from the advanced shaper.
+///
+/// The basic shaper emits one glyph per character, but numbers them by character relative to the
+/// start of each run, gives them all the attributes at the start of the run, and reads the
+/// ascent and descent from a different table.
+fn repair_basic_items(
+    items: &mut [Item],
+    text: &str,
+    attrs_list: &ct::AttrsList,
+    table: &ExtendedAttrsTable,
+    font_system: &mut ct::FontSystem,
+) {
+    let mut font_metrics = HashMap::new();
+
+    for (item, (start, c)) in items.iter_mut().zip(text.char_indices()) {
+        let attrs = attrs_list.get_span(start);
+        let glyph = &mut item.glyph;
+
+        glyph.start = start;
+        glyph.end = start + c.len_utf8();
+        glyph.color_opt = attrs.color_opt;
+        glyph.metadata = attrs.metadata;
+        item.font_size = table.get(attrs.metadata).font_size;
+
+        let metrics = font_metrics.entry(glyph.font_id).or_insert_with(|| {
+            font_system.get_font(glyph.font_id).map(|font| {
+                let face = font.rustybuzz();
+                let scale = face.units_per_em() as f32;
+                (
+                    f32::from(face.ascender()) / scale,
+                    -f32::from(face.descender()) / scale,
+                )
+            })
+        });
+
+        if let Some((ascent, descent)) = *metrics {
+            glyph.ascent = ascent;
+            glyph.descent = descent;
+        }
+    }
+}
+
+/// Copy a shaped glyph.
+pub(crate) fn copy_glyph(glyph: &ShapeGlyph) -> ShapeGlyph {
+    ShapeGlyph {
+        start: glyph.start,
+        end: glyph.end,
+        x_advance: glyph.x_advance,
+        y_advance: glyph.y_advance,
+        x_offset: glyph.x_offset,
+        y_offset: glyph.y_offset,
+        ascent: glyph.ascent,
+        descent: glyph.descent,
+        font_id: glyph.font_id,
+        glyph_id: glyph.glyph_id,
+        color_opt: glyph.color_opt,
+        metadata: glyph.metadata,
+    }
+}
+
+/// Break a list of logically ordered glyphs into lines.
+fn break_lines(mut items: Vec<Item>, max_width: f32, wrap: ct::Wrap) -> Vec<BrokenLine> {
+    let mut ranges = vec![];
+    let mut line_start = 0;
+
+    // The width of the line so far, and the width of the whitespace at the end of it.
+    let mut width = 0.0;
+    let mut pending = 0.0;
+
+    let mut i = 0;
+    while i < items.len() {
+        // Find the end of the current word.
+        let word_end = items[i..]
+            .iter()
+            .position(|item| item.word != items[i].word)
+            .map_or(items.len(), |len| i + len);
+        let word = &items[i..word_end];
+        let word_width: f32 = word.iter().map(Item::advance).sum();
+
+        // Whitespace can hang off of the end of the line.
+        if items[i].blank {
+            pending += word_width;
+            i = word_end;
+            continue;
+        }
+
+        if wrap == ct::Wrap::None || width + pending + word_width <= max_width {
+            width += pending + word_width;
+            pending = 0.0;
+            i = word_end;
+            continue;
+        }
+
+        match wrap {
+            ct::Wrap::Glyph => {
+                // Break the word between clusters.
+                for j in i..word_end {
+                    let advance = items[j].advance();
+                    let cluster_start = j == 0 || items[j].glyph.start != items[j - 1].glyph.start;
+
+                    if cluster_start && j > line_start && width + pending + advance > max_width {
+                        ranges.push((line_start..j, width));
+                        line_start = j;
+                        width = 0.0;
+                        pending = 0.0;
+                    }
+
+                    width += pending + advance;
+                    pending = 0.0;
+                }
+            }
+
+            _ => {
+                // Put the word on the next line.
+                if i > line_start {
+                    ranges.push((line_start..i, width));
+                    line_start = i;
+                }
+
+                width = word_width;
+                pending = 0.0;
+            }
+        }
+
+        i = word_end;
+    }
+
+    ranges.push((line_start..items.len(), width));
+
+    // Split the glyphs between the lines.
+    let mut lines = ranges
+        .into_iter()
+        .rev()
+        .map(|(range, width)| {
+            let items = items.split_off(range.start);
+            let hang = items
+                .iter()
+                .rposition(|item| !item.blank)
+                .map_or(0, |i| i + 1);
+
+            BrokenLine {
+                items,
+                hang,
+                width,
+                truncated: None,
+            }
+        })
+        .collect::<Vec<_>>();
+    lines.reverse();
+
+    lines
+}
+
+/// Truncate a line so that it fits into the maximum width, ending it with an ellipsis.
+///
+/// `paragraph` is the `BufferLine` that the line was broken from. If `hidden_end` is set, the
+/// ellipsis is added even if the line fits, and the text up to `hidden_end` is considered to be
+/// cut off.
+#[allow(clippy::too_many_arguments)]
+fn truncate_line(
+    line: &mut BrokenLine,
+    paragraph: &ct::BufferLine,
+    rtl: bool,
+    max_width: f32,
+    hidden_end: Option<usize>,
+    table: &ExtendedAttrsTable,
+    options: &Options,
+    font_system: &mut ct::FontSystem,
+) {
+    let attrs_list = paragraph.attrs_list();
+
+    // The ellipsis uses the attributes of the text it replaces. Since the amount of text it
+    // replaces depends on the size of the ellipsis, retry until they agree.
+    let mut cut = line.items.len();
+    let mut marker = vec![];
+    for _ in 0..3 {
+        let attrs_index = line
+            .items
+            .get(cut)
+            .or_else(|| line.items.last())
+            .map_or(0, |item| item.glyph.start);
+        marker = shape_marker(
+            attrs_list.get_span(attrs_index),
+            rtl,
+            table,
+            options,
+            font_system,
+        );
+
+        let marker_width = marker.iter().map(Item::advance).sum::<f32>();
+        let new_cut = fit_items(&line.items[..line.hang], max_width - marker_width);
+        if new_cut == cut {
+            break;
+        }
+
+        cut = new_cut;
+    }
+
+    // The ellipsis covers all of the text that was cut off.
+    let hidden = &line.items[cut..];
+    let kept_end = line.items[..cut].iter().map(|item| item.glyph.end).max();
+    let start = hidden
+        .iter()
+        .map(|item| item.glyph.start)
+        .min()
+        .or(kept_end)
+        .unwrap_or(0);
+    let end = hidden_end
+        .or_else(|| hidden.iter().map(|item| item.glyph.end).max())
+        .map_or(start, |end| end.max(start));
+
+    for item in &mut marker {
+        item.glyph.start = start;
+        item.glyph.end = end;
+    }
+
+    line.items.truncate(cut);
+    line.items.append(&mut marker);
+    line.hang = line.items.len();
+    line.width = line.items.iter().map(Item::advance).sum();
+    line.truncated = Some(start..end);
+}
+
+/// Find how many glyphs fit into a width, without splitting clusters or ending in whitespace.
+fn fit_items(items: &[Item], max_width: f32) -> usize {
+    let mut len = items.len();
+    let mut width = items.iter().map(Item::advance).sum::<f32>();
+
+    while len > 0 && (width > max_width || items[len - 1].blank) {
+        // Remove the last cluster.
+        let start = items[len - 1].glyph.start;
+        while len > 0 && items[len - 1].glyph.start == start {
+            len -= 1;
+            width -= items[len].advance();
+        }
+    }
+
+    len
+}
+
+/// Shape the marker placed at the end of truncated lines.
+fn shape_marker(
+    attrs: ct::Attrs<'_>,
+    rtl: bool,
+    table: &ExtendedAttrsTable,
+    options: &Options,
+    font_system: &mut ct::FontSystem,
+) -> Vec<Item> {
+    let attrs_list = ct::AttrsList::new(attrs);
+    let shape = ShapeLine::new(font_system, &options.ellipsis, &attrs_list, options.shaping);
+
+    // The marker goes at the end of the line, in the paragraph's direction.
+    let level = if rtl { Level::rtl() } else { Level::ltr() };
+    let mut items = logical_items(
+        &shape,
+        &options.ellipsis,
+        &attrs_list,
+        options.shaping,
+        table,
+        font_system,
+    );
+    for item in &mut items {
+        item.level = level;
+        item.blank = false;
+    }
+
+    items
+}
+
+/// Position the glyphs of a line.
+///
+/// Returns the glyphs in visual order and the X coordinate of the left edge of the line.
+fn position_line(
+    rtl: bool,
+    line: &BrokenLine,
+    align: ct::Align,
+    align_width: f32,
+    is_last: bool,
+) -> (Vec<LayoutGlyph>, f32) {
+    let items = &line.items;
+    let hang = line.hang;
+
+    // Hanging whitespace goes at the end of the line, in the paragraph's direction.
+    let levels = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            if i >= hang {
+                u8::from(rtl)
+            } else {
+                item.level.number()
+            }
+        })
+        .collect::<Vec<_>>();
+    let order = visual_order(&levels);
+
+    // Figure out how much extra space to give each space for justified text.
+    let blanks = items[..hang].iter().filter(|item| item.blank).count();
+    let justify =
+        if align == ct::Align::Justified && !is_last && blanks > 0 && align_width > line.width {
+            (align_width - line.width) / blanks as f32
+        } else {
+            0.0
+        };
+    let content_width = line.width + justify * blanks as f32;
+    let hang_width: f32 = items[hang..].iter().map(Item::advance).sum();
+
+    let offset = match (align, rtl) {
+        (ct::Align::Left, _) | (ct::Align::Justified, false) => 0.0,
+        (ct::Align::Right, _) | (ct::Align::Justified, true) => align_width - content_width,
+        (ct::Align::Center, _) => (align_width - content_width) / 2.0,
+    };
+
+    // In right-to-left lines, hanging whitespace ends up on the left.
+    let mut x = if rtl { offset - hang_width } else { offset };
+    let mut y = 0.0;
+
+    let glyphs = order
+        .into_iter()
+        .map(|i| {
+            let item = &items[i];
+            let mut width = item.advance();
+            if item.blank && i < hang {
+                width += justify;
+            }
+
+            let glyph = LayoutGlyph {
+                start: item.glyph.start,
+                end: item.glyph.end,
+                font_size: item.font_size,
+                font_id: item.glyph.font_id,
+                glyph_id: item.glyph.glyph_id,
+                x,
+                y,
+                w: width,
+                level: item.level,
+                x_offset: item.glyph.x_offset,
+                y_offset: item.glyph.y_offset,
+                color_opt: item.glyph.color_opt,
+                metadata: item.glyph.metadata,
+            };
+
+            x += width;
+            y += item.glyph.y_advance * item.font_size;
+
+            glyph
+        })
+        .collect();
+
+    (glyphs, offset)
+}
+
+/// Reorder a line from logical to visual order, given the embedding levels of its glyphs.
+///
+/// This implements rule L2 of the Unicode Bidirectional Algorithm.
+fn visual_order(levels: &[u8]) -> Vec<usize> {
+    let mut order = (0..levels.len()).collect::<Vec<_>>();
+
+    let max_level = levels.iter().copied().max().unwrap_or(0);
+    let min_odd_level = levels.iter().copied().min().unwrap_or(0) | 1;
+
+    // From the highest level down to the lowest odd level, reverse every run of glyphs at that
+    // level or higher.
+    for level in (min_odd_level..=max_level).rev() {
+        let mut i = 0;
+        while i < order.len() {
+            if levels[order[i]] < level {
+                i += 1;
+                continue;
+            }
+
+            let run_end = order[i..]
+                .iter()
+                .position(|&j| levels[j] < level)
+                .map_or(order.len(), |len| i + len);
+            order[i..run_end].reverse();
+            i = run_end;
+        }
+    }
+
+    order
+}
@@ -70,10 +70,12 @@
 //! The `is_loaded` method of [`Text`](crate::Text) can be used to check if the font system is
 //! fully loaded.
 //!
-//! # Limitations
+//! # Font Sizes
 //!
-//! The text does not support variable font sizes. Attempting to use these will result in emitting
-//! an error to the logs, and the text size not actually being changed.
+//! [`cosmic-text`] lays out an entire [`Buffer`] using a single font size. In order to support
+//! ranges of text with different font sizes, this crate lays out the shaped text itself. The
+//! [`TextLayout::layout_runs`] method should be used to get the laid out glyphs, rather than the
+//! `layout_runs` method of the underlying [`Buffer`].
 //!
 //! [`piet`]: https://docs.rs/piet
 //! [`cosmic-text`]: https://docs.rs/cosmic-text
@@ -98,12 +100,13 @@ use cosmic_text as ct;
 
 use std::fmt;
 
+pub use atlas::{DirtyRect, GlyphAtlas, GlyphQuad};
 pub use export_work::{CurrentThread, ExportWork};
-pub use lines::{LineProcessor, StyledLine};
+pub use lines::{DecorationLine, DecorationMetrics, DecorationStyle, LineProcessor, StyledLine};
 pub use metadata::Metadata;
-pub use text::Text;
-pub use text_layout::TextLayout;
-pub use text_layout_builder::TextLayoutBuilder;
+pub use text::{LoadedFace, Text};
+pub use text_layout::{BackgroundRect, GlyphOutline, LayoutRunIter, RangeRect, TextLayout};
+pub use text_layout_builder::{ShapingMode, TextLayoutBuilder, TextOverflow, WrapMode};
 
 #[cfg(feature = "rayon")]
 pub use export_work::Rayon;
@@ -183,13 +186,19 @@ impl Span {
     fn enter(self) {}
 }
 
+mod atlas;
 mod attributes;
 mod channel;
 #[cfg(feature = "embed_fonts")]
 mod embedded_fonts;
 mod export_work;
+mod features;
+mod layout;
 mod lines;
 mod metadata;
+mod raster;
+mod shaping;
+mod svg;
 mod text;
 mod text_layout;
 mod text_layout_builder;
@@ -205,6 +214,12 @@ pub(crate) enum FontError {
 
     /// Attribute index mismatch.
     InvalidAttributeIndex,
+
+    /// There is no room left in the glyph atlas.
+    AtlasFull,
+
+    /// There are more distinct sets of attributes than the glyph metadata can refer to.
+    TooManyStyles,
 }
 
 impl fmt::Display for FontError {
@@ -213,12 +228,172 @@ impl fmt::Display for FontError {
             Self::AlreadyBorrowed => f.write_str("the FontSystem is already mutably borrowed and cannot be accessed"),
             Self::NotLoaded => f.write_str("the FontSystem is not loaded yet, check is_loaded() before accessing or use wait_for_load()"),
             Self::InvalidAttributeIndex => f.write_str("invalid attribute index"),
+            Self::AtlasFull => f.write_str("there is no room left in the glyph atlas"),
+            Self::TooManyStyles => f.write_str("the layout uses too many distinct sets of attributes"),
         }
     }
 }
 
 impl std::error::Error for FontError {}
 
+/// The height of a line of text.
+///
+/// This can be set for all layouts using [`Text::set_line_height`], or for a single layout using
+/// [`TextLayoutBuilder::line_height`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineHeight {
+    /// A multiple of the largest font size in the line.
+    Relative(f64),
+
+    /// An absolute height, in points.
+    ///
+    /// Like font sizes, this is scaled by the DPI of the [`Text`].
+    Absolute(f64),
+}
+
+impl Default for LineHeight {
+    fn default() -> Self {
+        Self::Relative(1.0)
+    }
+}
+
+/// How the edges of glyphs are smoothed when they are rasterized.
+///
+/// This can be set using [`Text::set_antialiasing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum Antialiasing {
+    /// Glyphs are rendered with a single coverage value per pixel.
+    #[default]
+    Grayscale,
+
+    /// Glyphs are rendered with a coverage value for each channel, for horizontal LCD screens
+    /// with red on the left.
+    SubpixelRgb,
+
+    /// Glyphs are rendered with a coverage value for each channel, for horizontal LCD screens
+    /// with blue on the left.
+    SubpixelBgr,
+}
+
+/// Options for rasterizing glyphs.
+///
+/// These can be set for a [`Text`] using [`Text::set_render_options`]. They are used for the ink
+/// rectangles of layouts, for [`TextLayout::draw_into`], for [`TextLayout::glyph_outlines`] and
+/// for [`GlyphAtlas`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct RenderOptions {
+    /// Whether to hint glyphs, aligning their outlines to the pixel grid.
+    ///
+    /// This is enabled by default. Outlines are never hinted.
+    pub hinting: bool,
+
+    /// Whether to embolden glyphs when bold text uses a face that isn't bold.
+    ///
+    /// This is enabled by default.
+    pub synthetic_bold: bool,
+
+    /// Whether to slant glyphs when italic text uses a face that is upright.
+    ///
+    /// This is enabled by default.
+    pub synthetic_oblique: bool,
+
+    /// The gamma that the coverage of glyphs is corrected with.
+    ///
+    /// Coverage is raised to the power of `1 / gamma`, so values above `1.0` make text look
+    /// heavier. The default is `1.0`, which leaves coverage unchanged.
+    pub gamma: f32,
+
+    /// How much to sharpen the edges of glyphs, from `0.0` to `1.0`.
+    ///
+    /// The default is `0.0`, which leaves coverage unchanged.
+    pub contrast: f32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            hinting: true,
+            synthetic_bold: true,
+            synthetic_oblique: true,
+            gamma: 1.0,
+            contrast: 0.0,
+        }
+    }
+}
+
+/// An OpenType feature to apply when shaping a range of text.
+///
+/// These can be attached to ranges using [`TextLayoutBuilder::range_features`]. Features that
+/// are on by default, like `liga` and `kern`, can be turned off by giving them a value of `0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FontFeature {
+    tag: [u8; 4],
+    value: u32,
+}
+
+impl FontFeature {
+    /// Create a feature with the given tag and value.
+    ///
+    /// For most features, `1` turns the feature on and `0` turns it off. Features that pick one
+    /// of several alternates, like `salt`, use the value as the index of the alternate.
+    pub const fn new(tag: [u8; 4], value: u32) -> Self {
+        Self { tag, value }
+    }
+
+    /// Turn a feature on.
+    pub const fn enable(tag: [u8; 4]) -> Self {
+        Self::new(tag, 1)
+    }
+
+    /// Turn a feature off.
+    pub const fn disable(tag: [u8; 4]) -> Self {
+        Self::new(tag, 0)
+    }
+
+    /// Get the tag of this feature.
+    pub fn tag(&self) -> [u8; 4] {
+        self.tag
+    }
+
+    /// Get the value of this feature.
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+}
+
+/// A value for one of the variation axes of a variable font.
+///
+/// These can be attached to ranges using [`TextLayoutBuilder::range_variations`]. The `wght`
+/// axis follows the requested [`FontWeight`](piet::FontWeight) unless it is set explicitly.
+/// Axes that a font doesn't have are ignored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FontVariation {
+    tag: [u8; 4],
+    value: f32,
+}
+
+impl FontVariation {
+    /// Create a variation setting the axis with the given tag to a value.
+    ///
+    /// Values are in the units of the axis, such as `700.0` for `wght` or `75.0` for `wdth`, and
+    /// are clamped to the range that the font supports.
+    pub const fn new(tag: [u8; 4], value: f32) -> Self {
+        Self { tag, value }
+    }
+
+    /// Get the tag of the axis.
+    pub fn tag(&self) -> [u8; 4] {
+        self.tag
+    }
+
+    /// Get the value of the axis.
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+}
+
 fn cvt_color(p: piet::Color) -> ct::Color {
     let (r, g, b, a) = p.as_rgba8();
     ct::Color::rgba(r, g, b, a)
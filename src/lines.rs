@@ -26,60 +26,400 @@ use core::mem;
 use cosmic_text::LayoutGlyph;
 use line_straddler::{Glyph, GlyphStyle, Line as LsLine, LineGenerator, LineType};
 
-use piet::kurbo::{Line, Point, Rect};
+use piet::kurbo::{BezPath, Line, Point, Rect, Shape, Vec2};
 use piet::{Color, FontWeight};
 
+/// The ascent of a typical font, in ems, for placing lines when the font isn't known.
+pub(crate) const TYPICAL_ASCENT: f32 = 0.8;
+
+/// The descent of a typical font, in ems, for placing lines when the font isn't known.
+pub(crate) const TYPICAL_DESCENT: f32 = 0.2;
+
+/// The kind of a text decoration line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecorationLine {
+    /// A line below the text.
+    Underline,
+
+    /// A line above the text.
+    Overline,
+
+    /// A line through the middle of the text.
+    Strikethrough,
+}
+
+/// The style of the lines used to decorate text.
+///
+/// This can be set for ranges of text using
+/// [`TextLayoutBuilder::range_decoration_style`](crate::TextLayoutBuilder::range_decoration_style),
+/// and applies to every decoration line of the range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DecorationStyle {
+    /// A single, solid line.
+    #[default]
+    Solid,
+
+    /// Two parallel solid lines.
+    Double,
+
+    /// A line of square dots.
+    Dotted,
+
+    /// A line of dashes.
+    Dashed,
+
+    /// A wavy line.
+    ///
+    /// Use [`StyledLine::wavy_path`] to get its shape.
+    Wavy,
+}
+
+impl DecorationStyle {
+    /// Convert into the bits stored in a [`Metadata`].
+    pub(crate) fn to_bits(self) -> usize {
+        match self {
+            Self::Solid => 0,
+            Self::Double => 1,
+            Self::Dotted => 2,
+            Self::Dashed => 3,
+            Self::Wavy => 4,
+        }
+    }
+
+    /// Convert from the bits stored in a [`Metadata`].
+    pub(crate) fn from_bits(bits: usize) -> Self {
+        match bits {
+            1 => Self::Double,
+            2 => Self::Dotted,
+            3 => Self::Dashed,
+            4 => Self::Wavy,
+            _ => Self::Solid,
+        }
+    }
+}
+
+/// The positions and thickness of decoration lines, from the metrics of a font.
+///
+/// The offsets go from the baseline to the center of each line, in pixels, with Y pointing
+/// down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecorationMetrics {
+    /// The offset of the underline.
+    pub underline_offset: f32,
+
+    /// The offset of the overline.
+    pub overline_offset: f32,
+
+    /// The offset of the strikethrough.
+    pub strikethrough_offset: f32,
+
+    /// The thickness of the lines.
+    pub thickness: f32,
+}
+
+impl DecorationMetrics {
+    /// Get the metrics of the default instance of a font, at a font size in pixels.
+    pub fn new(font: &cosmic_text::Font, font_size: f32) -> Self {
+        Self::from_swash(font.as_swash().metrics(&[]), font_size)
+    }
+
+    /// Estimate the metrics from the ascent and descent of a font, in ems, at a font size in
+    /// pixels.
+    ///
+    /// This is for fonts that don't say where their lines go. The underline sits halfway into
+    /// the descent and is a quarter of it thick, the strikethrough is a third of the way up the
+    /// ascent, and the overline rests on top of the ascent.
+    pub fn fallback(ascent: f32, descent: f32, font_size: f32) -> Self {
+        let (ascent, descent) = (ascent * font_size, descent * font_size);
+        let thickness = descent / 4.0;
+
+        Self {
+            underline_offset: descent / 2.0,
+            overline_offset: thickness / 2.0 - ascent,
+            strikethrough_offset: -ascent / 3.0,
+            thickness,
+        }
+    }
+
+    /// Get the metrics from the metrics of a font.
+    ///
+    /// The underline comes from the `post` table, and the strikethrough from the `OS/2` table.
+    pub(crate) fn from_swash(metrics: swash::Metrics, font_size: f32) -> Self {
+        let units_per_em = f32::from(metrics.units_per_em.max(1));
+        let fallback = Self::fallback(
+            metrics.ascent / units_per_em,
+            metrics.descent / units_per_em,
+            font_size,
+        );
+        let metrics = metrics.scale(font_size);
+
+        // Fonts without a `post` or `OS/2` table don't say how thick lines are.
+        if metrics.stroke_size <= 0.0 {
+            return fallback;
+        }
+
+        // The font gives the positions of the tops of the lines, with Y pointing up.
+        let half = metrics.stroke_size / 2.0;
+        let strikethrough_offset = if metrics.strikeout_offset != 0.0 {
+            half - metrics.strikeout_offset
+        } else if metrics.x_height > 0.0 {
+            -metrics.x_height / 2.0
+        } else {
+            fallback.strikethrough_offset
+        };
+
+        Self {
+            underline_offset: half - metrics.underline_offset,
+            overline_offset: half - metrics.ascent,
+            strikethrough_offset,
+            thickness: metrics.stroke_size,
+        }
+    }
+
+    /// Get the offset of a kind of line.
+    fn offset(&self, kind: DecorationLine) -> f32 {
+        match kind {
+            DecorationLine::Underline => self.underline_offset,
+            DecorationLine::Overline => self.overline_offset,
+            DecorationLine::Strikethrough => self.strikethrough_offset,
+        }
+    }
+}
+
 /// A bundle between a line and a glyph styling.
+///
+/// More fields may be added in the future, so this can only be created by a [`LineProcessor`].
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub struct StyledLine {
-    /// The line.
+    /// The line, along the center of the stroke.
     pub line: Line,
 
+    /// The thickness of the line.
+    pub thickness: f64,
+
     /// The color of the line.
+    ///
+    /// This is the color set for this kind of line, or else the color of the text.
     pub color: Color,
 
-    /// Whether or not the line is bolded.
+    /// The weight of the decorated text.
     pub bold: FontWeight,
 
     /// The size of the font, in pixels.
     pub font_size: f32,
+
+    /// Which decoration this line is.
+    pub kind: DecorationLine,
+
+    /// How the line should be drawn.
+    pub style: DecorationStyle,
 }
 
 impl StyledLine {
     /// Represent this styled line as a rectangle.
+    ///
+    /// This is the area covered by a [`Solid`](DecorationStyle::Solid) line. Use
+    /// [`rects`](Self::rects) or [`wavy_path`](Self::wavy_path) to draw the other styles.
     pub fn into_rect(self) -> Rect {
-        const FONT_WEIGHT_MULTIPLIER: f32 = 0.05;
-        const OFFSET_MULTIPLIER: f32 = -0.83;
+        let half = self.thickness / 2.0;
+        Rect::from_points(self.line.p0 - (0.0, half), self.line.p1 + (0.0, half))
+    }
+
+    /// Get the area covered by this line, whatever its style.
+    pub fn bounds(self) -> Rect {
+        match self.style {
+            DecorationStyle::Wavy => self
+                .wavy_path()
+                .bounding_box()
+                .inflate(0.0, self.thickness / 2.0),
+            _ => self
+                .rects()
+                .into_iter()
+                .fold(self.into_rect(), |bounds, rect| bounds.union(rect)),
+        }
+    }
+
+    /// Get the rectangles to fill in order to draw this line.
+    ///
+    /// [`Wavy`](DecorationStyle::Wavy) lines can't be drawn with rectangles, so this returns
+    /// nothing for them.
+    pub fn rects(self) -> Vec<Rect> {
+        let rect = self.into_rect();
+        let thickness = rect.height();
+
+        // Split the line into segments that start every `period` pixels.
+        let segments = |length: f64, period: f64| {
+            let mut x = rect.x0;
+            let mut rects = vec![];
+            while x < rect.x1 {
+                rects.push(Rect::new(x, rect.y0, (x + length).min(rect.x1), rect.y1));
+                x += period;
+            }
+            rects
+        };
+
+        match self.style {
+            DecorationStyle::Solid => vec![rect],
+            DecorationStyle::Double => {
+                // The second line goes further away from the text.
+                let offset = match self.kind {
+                    DecorationLine::Overline => -2.0 * thickness,
+                    _ => 2.0 * thickness,
+                };
+                vec![rect, rect + Vec2::new(0.0, offset)]
+            }
+            DecorationStyle::Dotted => segments(thickness, 2.0 * thickness),
+            DecorationStyle::Dashed => segments(3.0 * thickness, 5.0 * thickness),
+            DecorationStyle::Wavy => vec![],
+        }
+    }
 
-        let offset = self.font_size * OFFSET_MULTIPLIER;
-        let width = self.font_size
-            * (self.bold.to_raw() as f32 / FontWeight::NORMAL.to_raw() as f32)
-            * FONT_WEIGHT_MULTIPLIER;
+    /// Get the path that a [`Wavy`](DecorationStyle::Wavy) line follows.
+    ///
+    /// The path is centered on the line and should be stroked with a width of
+    /// [`thickness`](Self::thickness). It is returned regardless of the style of the line.
+    pub fn wavy_path(self) -> BezPath {
+        let rect = self.into_rect();
+        let thickness = rect.height();
+        let y = rect.center().y;
+        let half_wave = 3.0 * thickness;
+        let amplitude = thickness;
 
-        let mut p0 = self.line.p0;
-        let mut p1 = self.line.p1;
-        p0.y += f64::from(offset);
-        p1.y = p0.y - f64::from(width);
-        Rect::from_points(p0, p1)
+        let mut path = BezPath::new();
+        path.move_to((rect.x0, y));
+
+        let mut x = rect.x0;
+        let mut up = true;
+        while x < rect.x1 {
+            let end = (x + half_wave).min(rect.x1);
+
+            // Shorter final waves keep the same steepness.
+            let height = 2.0 * amplitude * (end - x) / half_wave;
+            let control_y = if up { y - height } else { y + height };
+            path.quad_to(((x + end) / 2.0, control_y), (end, y));
+
+            x = end;
+            up = !up;
+        }
+
+        path
     }
 }
 
-/// State for text processing underlines and strikethroughs using [`line-straddler`].
+/// State for text processing decoration lines using [`line-straddler`].
 ///
 /// [`line-straddler`]: https://crates.io/crates/line-straddler
 #[derive(Debug)]
 pub struct LineProcessor {
     /// State for the underline.
-    underline: LineGenerator,
+    underline: Decoration,
+
+    /// State for the overline.
+    overline: Decoration,
 
     /// State for the strikethrough.
-    strikethrough: LineGenerator,
+    strikethrough: Decoration,
 
     /// The lines to draw.
     lines: Vec<StyledLine>,
+}
 
-    /// The last glyph size processed.
-    last_glyph_size: f32,
+/// State for one kind of decoration line.
+#[derive(Debug)]
+struct Decoration {
+    /// The generator for the line.
+    generator: LineGenerator,
+
+    /// Which decoration this is.
+    kind: DecorationLine,
+
+    /// How the line currently being generated looks.
+    look: LineLook,
+}
+
+/// Everything about a line that isn't tracked by [`line-straddler`].
+///
+/// [`line-straddler`]: https://crates.io/crates/line-straddler
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LineLook {
+    /// The style of the line.
+    style: DecorationStyle,
+
+    /// The metrics of the font.
+    metrics: DecorationMetrics,
+
+    /// The Y coordinate of the baseline.
+    baseline: f32,
+
+    /// The size of the font, in pixels.
+    font_size: f32,
+}
+
+impl Decoration {
+    fn new(kind: DecorationLine) -> Self {
+        let line_type = match kind {
+            DecorationLine::Underline => LineType::Underline,
+            DecorationLine::Overline => LineType::Overline,
+            DecorationLine::Strikethrough => LineType::StrikeThrough,
+        };
+
+        Self {
+            generator: LineGenerator::new(line_type),
+            kind,
+            look: LineLook {
+                style: DecorationStyle::Solid,
+                metrics: DecorationMetrics::fallback(TYPICAL_ASCENT, TYPICAL_DESCENT, 0.0),
+                baseline: 0.0,
+                font_size: 0.0,
+            },
+        }
+    }
+
+    /// Add a glyph to the line, or end the line if the glyph doesn't have this decoration.
+    fn handle(&mut self, glyph: Option<Glyph>, look: LineLook) -> Option<StyledLine> {
+        let line = match glyph {
+            // Lines with different styles or metrics can't be merged.
+            Some(glyph) if look.style == self.look.style && look.metrics == self.look.metrics => {
+                self.generator.add_glyph(glyph)
+            }
+            Some(glyph) => {
+                let line = self.generator.pop_line();
+                self.generator.add_glyph(glyph);
+                line
+            }
+            None => self.generator.pop_line(),
+        };
+
+        let line = line.map(|line| self.cvt_line(line));
+        self.look = look;
+        line
+    }
+
+    /// Finish the current line.
+    fn finish(&mut self) -> Option<StyledLine> {
+        self.generator.pop_line().map(|line| self.cvt_line(line))
+    }
+
+    fn cvt_line(&self, ls_line: LsLine) -> StyledLine {
+        let look = &self.look;
+        let y = f64::from(look.baseline + look.metrics.offset(self.kind));
+        let line = Line {
+            p0: Point::new(ls_line.start_x.into(), y),
+            p1: Point::new(ls_line.end_x.into(), y),
+        };
+
+        StyledLine {
+            line,
+            thickness: look.metrics.thickness.into(),
+            color: cvt_color(ls_line.style.color),
+            bold: FontWeight::new(ls_line.style.boldness),
+            font_size: look.font_size,
+            kind: self.kind,
+            style: look.style,
+        }
+    }
 }
 
 impl Default for LineProcessor {
@@ -92,93 +432,107 @@ impl LineProcessor {
     /// Create a new, empty state.
     pub fn new() -> Self {
         Self {
-            underline: LineGenerator::new(LineType::Underline),
-            strikethrough: LineGenerator::new(LineType::StrikeThrough),
+            underline: Decoration::new(DecorationLine::Underline),
+            overline: Decoration::new(DecorationLine::Overline),
+            strikethrough: Decoration::new(DecorationLine::Strikethrough),
             lines: Vec::new(),
-            last_glyph_size: 0.0,
         }
     }
 
     /// Handle a glyph.
+    ///
+    /// The font of the glyph isn't known, so the lines are placed using
+    /// [`DecorationMetrics::fallback`] with the ascent and descent of a typical font. Use
+    /// [`handle_glyph_with_metrics`] to use the metrics of the font instead.
+    ///
+    /// [`handle_glyph_with_metrics`]: Self::handle_glyph_with_metrics
     pub fn handle_glyph(&mut self, glyph: &LayoutGlyph, line_y: f32, color: cosmic_text::Color) {
+        let metrics = DecorationMetrics::fallback(TYPICAL_ASCENT, TYPICAL_DESCENT, glyph.font_size);
+        self.handle_glyph_with_metrics(glyph, line_y, color, metrics);
+    }
+
+    /// Handle a glyph, placing its lines using the metrics of its font.
+    ///
+    /// Lines are drawn in the color of the glyph, or `color` if it doesn't have one. Use
+    /// [`TextLayout::decoration_lines`](crate::TextLayout::decoration_lines) to get lines that
+    /// use the colors set with
+    /// [`TextLayoutBuilder::range_decoration_color`](crate::TextLayoutBuilder::range_decoration_color).
+    pub fn handle_glyph_with_metrics(
+        &mut self,
+        glyph: &LayoutGlyph,
+        line_y: f32,
+        color: cosmic_text::Color,
+        metrics: DecorationMetrics,
+    ) {
+        let color = glyph.color_opt.unwrap_or(color);
+        let [r, g, b, a] = [color.r(), color.g(), color.b(), color.a()];
+        let color = Color::rgba8(r, g, b, a);
+        self.handle_styled_glyph(glyph, line_y, |_| color, metrics);
+    }
+
+    /// Handle a glyph, drawing each kind of line in the color returned by `color`.
+    pub(crate) fn handle_styled_glyph(
+        &mut self,
+        glyph: &LayoutGlyph,
+        line_y: f32,
+        color: impl Fn(DecorationLine) -> Color,
+        metrics: DecorationMetrics,
+    ) {
         // Get the metadata.
         let metadata = Metadata::from_raw(glyph.metadata);
         let font_size = glyph.font_size;
-        let glyph = Glyph {
-            line_y,
-            font_size,
-            width: glyph.w,
-            x: glyph.x,
-            style: GlyphStyle {
-                boldness: metadata.boldness().to_raw(),
-                color: match glyph.color_opt {
-                    Some(color) => {
-                        let [r, g, b, a] = [color.r(), color.g(), color.b(), color.a()];
-                        line_straddler::Color::rgba(r, g, b, a)
-                    }
-
-                    None => {
-                        let [r, g, b, a] = [color.r(), color.g(), color.b(), color.a()];
-                        line_straddler::Color::rgba(r, g, b, a)
-                    }
+        let glyph = |kind: DecorationLine| {
+            let (r, g, b, a) = color(kind).as_rgba8();
+            Glyph {
+                line_y,
+                font_size,
+                width: glyph.w,
+                x: glyph.x,
+                style: GlyphStyle {
+                    boldness: metadata.boldness().to_raw(),
+                    color: line_straddler::Color::rgba(r, g, b, a),
                 },
-            },
+            }
         };
 
-        let Self {
-            underline,
-            strikethrough,
-            lines,
-            last_glyph_size,
-        } = self;
-
-        let handle_meta = |generator: &mut LineGenerator, has_it| {
-            let line = if has_it {
-                generator.add_glyph(glyph)
-            } else {
-                generator.pop_line()
-            };
-
-            line.map(|line| cvt_line(line, font_size))
+        let look = LineLook {
+            style: metadata.decoration_style(),
+            metrics,
+            baseline: line_y,
+            font_size,
         };
+        let has = |has_it: bool, kind| if has_it { Some(glyph(kind)) } else { None };
 
-        let underline = handle_meta(underline, metadata.underline());
-        let strikethrough = handle_meta(strikethrough, metadata.strikethrough());
+        let underline = self
+            .underline
+            .handle(has(metadata.underline(), DecorationLine::Underline), look);
+        let overline = self
+            .overline
+            .handle(has(metadata.overline(), DecorationLine::Overline), look);
+        let strikethrough = self.strikethrough.handle(
+            has(metadata.strikethrough(), DecorationLine::Strikethrough),
+            look,
+        );
 
-        lines.extend(underline);
-        lines.extend(strikethrough);
-        *last_glyph_size = font_size;
+        self.lines.extend(underline);
+        self.lines.extend(overline);
+        self.lines.extend(strikethrough);
     }
 
     /// Take the associated lines.
     pub fn lines(&mut self) -> Vec<StyledLine> {
         // Pop the last lines.
-        let underline = self.underline.pop_line();
-        let strikethrough = self.strikethrough.pop_line();
-        let font_size = self.last_glyph_size;
-        self.lines
-            .extend(underline.map(|line| cvt_line(line, font_size)));
-        self.lines
-            .extend(strikethrough.map(|line| cvt_line(line, font_size)));
+        let underline = self.underline.finish();
+        let overline = self.overline.finish();
+        let strikethrough = self.strikethrough.finish();
+        self.lines.extend(underline);
+        self.lines.extend(overline);
+        self.lines.extend(strikethrough);
 
         mem::take(&mut self.lines)
     }
 }
 
-fn cvt_line(ls_line: LsLine, font_size: f32) -> StyledLine {
-    let line = Line {
-        p0: Point::new(ls_line.start_x.into(), ls_line.y.into()),
-        p1: Point::new(ls_line.end_x.into(), ls_line.y.into()),
-    };
-
-    StyledLine {
-        line,
-        color: cvt_color(ls_line.style.color),
-        bold: FontWeight::new(ls_line.style.boldness),
-        font_size,
-    }
-}
-
 fn cvt_color(color: line_straddler::Color) -> Color {
     let [r, g, b, a] = color.components();
     Color::rgba8(r, g, b, a)
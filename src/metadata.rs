@@ -16,6 +16,8 @@
 // You should have received a copy of the GNU Lesser General Public License and the Mozilla
 // Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::lines::DecorationStyle;
+
 use core::fmt;
 use piet::FontWeight;
 
@@ -29,8 +31,11 @@ impl fmt::Debug for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Metadata")
             .field("underline", &self.underline())
+            .field("overline", &self.overline())
             .field("strikethrough", &self.strikethrough())
+            .field("decoration_style", &self.decoration_style())
             .field("boldness", &self.boldness())
+            .field("italic", &self.italic())
             .finish()
     }
 }
@@ -39,6 +44,20 @@ const FONT_WEIGHT_SIZE: usize = 10;
 const FONT_WEIGHT_MASK: usize = 0b1111111111;
 const UNDERLINE: usize = 1 << FONT_WEIGHT_SIZE;
 const STRIKETHROUGH: usize = 1 << (FONT_WEIGHT_SIZE + 1);
+const ITALIC: usize = 1 << (FONT_WEIGHT_SIZE + 2);
+const OVERLINE: usize = 1 << (FONT_WEIGHT_SIZE + 3);
+const DECORATION_STYLE_SHIFT: usize = FONT_WEIGHT_SIZE + 4;
+const DECORATION_STYLE_MASK: usize = 0b111 << DECORATION_STYLE_SHIFT;
+
+// The upper bits are used as an index into the layout's table of extended attributes. Twenty
+// bits are left free below it for flags.
+const STYLE_INDEX_SHIFT: usize = 20;
+const FLAGS_MASK: usize = (1 << STYLE_INDEX_SHIFT) - 1;
+
+/// The number of style indices that fit in the metadata.
+///
+/// Only twelve bits are left on 32-bit targets.
+pub(crate) const STYLE_INDEX_LIMIT: usize = 1 << (usize::BITS as usize - STYLE_INDEX_SHIFT);
 
 impl Metadata {
     /// Create a new, empty metadata.
@@ -74,12 +93,36 @@ impl Metadata {
         }
     }
 
+    /// Set the "overline" bit.
+    pub fn set_overline(&mut self, overline: bool) {
+        if overline {
+            self.0 |= OVERLINE;
+        } else {
+            self.0 &= !OVERLINE;
+        }
+    }
+
+    /// Set the style of the decoration lines.
+    pub fn set_decoration_style(&mut self, style: DecorationStyle) {
+        self.0 &= !DECORATION_STYLE_MASK;
+        self.0 |= style.to_bits() << DECORATION_STYLE_SHIFT;
+    }
+
     /// Set the boldness of the font.
     pub fn set_boldness(&mut self, boldness: FontWeight) {
         self.0 &= !FONT_WEIGHT_MASK;
         self.0 |= usize::from(boldness.to_raw());
     }
 
+    /// Set the "italic" bit.
+    pub fn set_italic(&mut self, italic: bool) {
+        if italic {
+            self.0 |= ITALIC;
+        } else {
+            self.0 &= !ITALIC;
+        }
+    }
+
     /// Is the "underline" bit set?
     pub fn underline(&self) -> bool {
         self.0 & UNDERLINE != 0
@@ -90,8 +133,39 @@ impl Metadata {
         self.0 & STRIKETHROUGH != 0
     }
 
+    /// Is the "overline" bit set?
+    pub fn overline(&self) -> bool {
+        self.0 & OVERLINE != 0
+    }
+
+    /// Get the style of the decoration lines.
+    pub fn decoration_style(&self) -> DecorationStyle {
+        DecorationStyle::from_bits((self.0 & DECORATION_STYLE_MASK) >> DECORATION_STYLE_SHIFT)
+    }
+
+    /// Is the "italic" bit set?
+    pub fn italic(&self) -> bool {
+        self.0 & ITALIC != 0
+    }
+
     /// Get the boldness of the font.
     pub fn boldness(&self) -> FontWeight {
         FontWeight::new((self.0 & FONT_WEIGHT_MASK) as u16)
     }
+
+    /// Set the index into the table of extended attributes.
+    pub(crate) fn set_style_index(&mut self, index: usize) {
+        debug_assert!(
+            index < STYLE_INDEX_LIMIT,
+            "style index {} is too large",
+            index
+        );
+        self.0 &= FLAGS_MASK;
+        self.0 |= index << STYLE_INDEX_SHIFT;
+    }
+
+    /// Get the index into the table of extended attributes.
+    pub(crate) fn style_index(&self) -> usize {
+        self.0 >> STYLE_INDEX_SHIFT
+    }
 }
@@ -0,0 +1,711 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Rasterizing glyphs and drawing them into pixel buffers.
+
+use crate::attributes::ExtendedAttrsTable;
+use crate::metadata::Metadata;
+use crate::{Antialiasing, FontVariation, RenderOptions};
+
+use cosmic_text as ct;
+use ct::{fontdb, CacheKey};
+
+use piet::kurbo::{BezPath, PathEl, Point, Rect};
+use piet::Color;
+
+use swash::scale::image::{Content, Image as SwashImage};
+use swash::scale::outline::Outline as SwashOutline;
+use swash::scale::{Render, ScaleContext, Source, StrikeWith};
+use swash::zeno::{Angle, Command, Format, Mask, PathData, Stroke, Transform, Vector};
+use swash::FontRef;
+
+use tinyvec::TinyVec;
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::hash::Hash;
+
+/// The font size is divided by this to get the strength of synthetic bold, in pixels.
+const EMBOLDEN_DIVISOR: f32 = 24.0;
+
+/// The angle that synthetic oblique glyphs are slanted by, in degrees.
+const OBLIQUE_ANGLE: f32 = 14.0;
+
+/// The tag of the weight axis of variable fonts.
+const WEIGHT_AXIS: swash::Tag = swash::tag_from_bytes(b"wght");
+
+/// The number of glyph images kept around before the least recently used ones are dropped.
+const MAX_CACHED_IMAGES: usize = 4096;
+
+/// The number of glyph outlines kept around before the least recently used ones are dropped.
+const MAX_CACHED_OUTLINES: usize = 4096;
+
+/// Cached state for rasterizing glyphs.
+pub(crate) struct Rasterizer {
+    /// The context used to scale glyphs.
+    context: ScaleContext,
+
+    /// How glyph images are antialiased.
+    antialiasing: Antialiasing,
+
+    /// The options that glyph images are rendered with.
+    options: RenderOptions,
+
+    /// Maps coverage values to gamma and contrast corrected ones.
+    coverage: Option<[u8; 256]>,
+
+    /// Glyph images that have already been rendered.
+    images: GlyphCache<(CacheKey, Synthesis), Option<SwashImage>>,
+
+    /// Glyph outlines that have already been scaled, keyed by font, glyph and font size.
+    outlines: GlyphCache<(fontdb::ID, u16, u32, Synthesis), Vec<OutlineLayer>>,
+
+    /// Scratch space for scaling outlines.
+    swash_outline: SwashOutline,
+}
+
+/// Styles that a glyph's face doesn't have by default, which are synthesized instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Synthesis {
+    /// Embolden the glyph.
+    pub(crate) bold: bool,
+
+    /// Slant the glyph.
+    pub(crate) oblique: bool,
+
+    /// The instance of a variable font to use.
+    pub(crate) variations: Variations,
+}
+
+impl Synthesis {
+    /// Figure out which of the styles requested for a glyph its face lacks.
+    ///
+    /// Variable fonts are set to the requested instance. Fonts with a weight axis are never
+    /// emboldened.
+    pub(crate) fn for_glyph(
+        glyph: &ct::LayoutGlyph,
+        options: &RenderOptions,
+        table: &ExtendedAttrsTable,
+        system: &mut ct::FontSystem,
+    ) -> Self {
+        let (weight, style) = match system.db().face(glyph.font_id) {
+            Some(face) => (face.weight, face.style),
+            None => return Self::default(),
+        };
+        let (variations, variable_weight) = match system.get_font(glyph.font_id) {
+            Some(font) => {
+                let font = font.as_swash();
+                (
+                    Variations::new(font, table.variations(glyph.metadata)),
+                    font.variations().any(|axis| axis.tag() == WEIGHT_AXIS),
+                )
+            }
+            None => (Variations::default(), false),
+        };
+        let metadata = Metadata::from_raw(glyph.metadata);
+
+        Self {
+            bold: options.synthetic_bold
+                && metadata.boldness().to_raw() >= fontdb::Weight::SEMIBOLD.0
+                && weight < fontdb::Weight::SEMIBOLD
+                && !variable_weight,
+            oblique: options.synthetic_oblique
+                && metadata.italic()
+                && style == fontdb::Style::Normal,
+            variations,
+        }
+    }
+
+    /// Get the strength of the emboldening, in pixels.
+    fn strength(&self, font_size: f32) -> f32 {
+        if self.bold {
+            font_size / EMBOLDEN_DIVISOR
+        } else {
+            0.0
+        }
+    }
+
+    /// Get the transform that slants the glyph, with Y pointing up.
+    fn transform(&self) -> Option<Transform> {
+        if self.oblique {
+            Some(Transform::skew(
+                Angle::from_degrees(OBLIQUE_ANGLE),
+                Angle::ZERO,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Apply the styles to an outline.
+    pub(crate) fn apply(&self, outline: &mut SwashOutline, font_size: f32) {
+        if self.bold {
+            let strength = self.strength(font_size);
+            outline.embolden(strength, strength);
+        }
+
+        if let Some(transform) = self.transform() {
+            outline.transform(&transform);
+        }
+    }
+}
+
+/// The normalized coordinates of an instance of a variable font.
+///
+/// This is empty for the default instance, including for fonts that aren't variable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Variations(TinyVec<[i16; 4]>);
+
+impl Variations {
+    /// Figure out the instance of a font that the given axis values select.
+    ///
+    /// Later values for an axis replace earlier ones.
+    pub(crate) fn new(font: FontRef<'_>, settings: impl Iterator<Item = FontVariation>) -> Self {
+        let coords = font
+            .variations()
+            .normalized_coords(
+                settings.map(|setting| (swash::tag_from_bytes(&setting.tag()), setting.value())),
+            )
+            .collect::<TinyVec<[i16; 4]>>();
+
+        if coords.iter().all(|&coord| coord == 0) {
+            Self::default()
+        } else {
+            Self(coords)
+        }
+    }
+
+    /// Tell whether this is the default instance.
+    pub(crate) fn is_default(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the normalized coordinates, one for each axis of the font.
+    pub(crate) fn coords(&self) -> &[i16] {
+        &self.0
+    }
+}
+
+/// One layer of a glyph's outline.
+pub(crate) struct OutlineLayer {
+    /// The path of the layer, with the glyph's origin at zero and Y pointing down.
+    pub(crate) path: BezPath,
+
+    /// The color of the layer, or `None` if the glyph's own color is used.
+    pub(crate) color: Option<Color>,
+}
+
+impl Rasterizer {
+    pub(crate) fn new() -> Self {
+        Self {
+            context: ScaleContext::new(),
+            antialiasing: Antialiasing::default(),
+            options: RenderOptions::default(),
+            coverage: None,
+            images: GlyphCache::new(MAX_CACHED_IMAGES),
+            outlines: GlyphCache::new(MAX_CACHED_OUTLINES),
+            swash_outline: SwashOutline::new(),
+        }
+    }
+
+    /// Set how glyph images are rendered.
+    ///
+    /// Images that were rendered with other settings are thrown away.
+    pub(crate) fn configure(&mut self, antialiasing: Antialiasing, options: RenderOptions) {
+        if antialiasing == self.antialiasing && options == self.options {
+            return;
+        }
+
+        self.antialiasing = antialiasing;
+        self.options = options;
+        self.coverage = coverage_table(&options);
+        self.images.clear();
+    }
+
+    /// Figure out which styles to synthesize for a glyph.
+    pub(crate) fn synthesis(
+        &self,
+        glyph: &ct::LayoutGlyph,
+        table: &ExtendedAttrsTable,
+        system: &mut ct::FontSystem,
+    ) -> Synthesis {
+        Synthesis::for_glyph(glyph, &self.options, table, system)
+    }
+
+    /// Get the outline of a glyph, scaled to a font size in pixels.
+    ///
+    /// Color glyphs have one layer for each color. Outlines are not hinted.
+    pub(crate) fn outline(
+        &mut self,
+        font_id: fontdb::ID,
+        glyph_id: u16,
+        font_size: f32,
+        synthesis: Synthesis,
+        system: &mut ct::FontSystem,
+    ) -> &[OutlineLayer] {
+        let context = &mut self.context;
+        let outline = &mut self.swash_outline;
+        let key = (font_id, glyph_id, font_size.to_bits(), synthesis);
+
+        self.outlines
+            .get_or_insert_with(key, |(_, _, _, synthesis)| {
+                let mut layers = vec![];
+                if let Some(font) = system.get_font(font_id) {
+                    let font = font.as_swash();
+                    let mut scaler = context
+                        .builder(font)
+                        .size(font_size)
+                        .normalized_coords(synthesis.variations.coords())
+                        .build();
+
+                    if scaler.scale_color_outline_into(glyph_id, outline) {
+                        synthesis.apply(outline, font_size);
+                        let palette = font.color_palettes().next();
+                        layers.extend((0..outline.len()).filter_map(|i| outline.get(i)).map(
+                            |layer| OutlineLayer {
+                                path: cvt_path(layer.path()),
+                                color: layer.color_index().zip(palette).map(|(index, palette)| {
+                                    let [r, g, b, a] = palette.get(index);
+                                    Color::rgba8(r, g, b, a)
+                                }),
+                            },
+                        ));
+                    } else if scaler.scale_outline_into(glyph_id, outline) {
+                        synthesis.apply(outline, font_size);
+                        layers.push(OutlineLayer {
+                            path: cvt_path(outline.path()),
+                            color: None,
+                        });
+                    } else {
+                        warn!("Failed to get the outline of glyph {}", glyph_id);
+                    }
+                }
+
+                layers
+            })
+    }
+
+    /// Get the rendered image for a glyph.
+    ///
+    /// Glyphs without colors of their own are rendered as subpixel masks when subpixel
+    /// antialiasing is enabled.
+    pub(crate) fn image(
+        &mut self,
+        key: CacheKey,
+        synthesis: Synthesis,
+        system: &mut ct::FontSystem,
+    ) -> Option<&SwashImage> {
+        let format = match self.antialiasing {
+            Antialiasing::Grayscale => Format::Alpha,
+            Antialiasing::SubpixelRgb => Format::Subpixel,
+            Antialiasing::SubpixelBgr => Format::subpixel_bgra(),
+        };
+
+        let context = &mut self.context;
+        let hinting = self.options.hinting;
+        let coverage = &self.coverage;
+
+        self.images
+            .get_or_insert_with((key, synthesis), |(key, synthesis)| {
+                let font_size = f32::from_bits(key.font_size_bits);
+                let mut image = system.get_font(key.font_id).and_then(|font| {
+                    let mut scaler = context
+                        .builder(font.as_swash())
+                        .size(font_size)
+                        .hint(hinting)
+                        .normalized_coords(synthesis.variations.coords())
+                        .build();
+
+                    Render::new(&[
+                        Source::ColorOutline(0),
+                        Source::ColorBitmap(StrikeWith::BestFit),
+                        Source::Outline,
+                    ])
+                    .format(format)
+                    .offset(Vector::new(key.x_bin.as_float(), key.y_bin.as_float()))
+                    .embolden(synthesis.strength(font_size))
+                    .transform(synthesis.transform())
+                    .render(&mut scaler, key.glyph_id)
+                });
+
+                match (&mut image, coverage) {
+                    (None, _) => {
+                        warn!("Failed to render glyph {}", key.glyph_id);
+                    }
+                    (Some(image), Some(table)) => correct_coverage(image, table),
+                    _ => {}
+                }
+
+                image
+            })
+            .as_ref()
+    }
+}
+
+/// A cache of glyph data that drops the least recently used half of its entries once it is full.
+struct GlyphCache<K, V> {
+    /// The cached values, and when each was last used.
+    entries: HashMap<K, (V, u64)>,
+
+    /// The number of entries to keep at most.
+    limit: usize,
+
+    /// The number of lookups so far.
+    clock: u64,
+}
+
+impl<K: Hash + Eq, V> GlyphCache<K, V> {
+    fn new(limit: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            limit,
+            clock: 0,
+        }
+    }
+
+    /// Get the value for a key, creating it if it isn't cached.
+    fn get_or_insert_with(&mut self, key: K, make: impl FnOnce(&K) -> V) -> &mut V {
+        self.clock += 1;
+        if self.entries.len() >= self.limit && !self.entries.contains_key(&key) {
+            self.evict();
+        }
+
+        let (value, last_used) = match self.entries.entry(key) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                let value = make(v.key());
+                v.insert((value, 0))
+            }
+        };
+
+        *last_used = self.clock;
+        value
+    }
+
+    /// Drop the least recently used half of the entries.
+    fn evict(&mut self) {
+        let mut uses = self
+            .entries
+            .values()
+            .map(|&(_, last_used)| last_used)
+            .collect::<Vec<_>>();
+        let middle = uses.len() / 2;
+        let cutoff = *uses.select_nth_unstable(middle).1;
+
+        self.entries
+            .retain(|_, &mut (_, last_used)| last_used >= cutoff);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// A buffer of premultiplied RGBA pixels to draw into.
+pub(crate) struct Canvas<'a> {
+    /// The pixel data.
+    pixels: &'a mut [u8],
+
+    /// The width of the buffer, in pixels.
+    width: usize,
+
+    /// The height of the buffer, in pixels.
+    height: usize,
+
+    /// The number of bytes between the starts of two rows.
+    stride: usize,
+}
+
+impl<'a> Canvas<'a> {
+    /// Wrap a pixel buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer is too small for the given dimensions.
+    pub(crate) fn new(pixels: &'a mut [u8], width: usize, height: usize, stride: usize) -> Self {
+        assert!(
+            stride >= width * 4,
+            "stride of {} bytes is too small for {} pixels",
+            stride,
+            width
+        );
+
+        if height > 0 {
+            let needed = stride * (height - 1) + width * 4;
+            assert!(
+                pixels.len() >= needed,
+                "buffer of {} bytes is too small, need {} bytes",
+                pixels.len(),
+                needed
+            );
+        }
+
+        Self {
+            pixels,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    /// Blend a premultiplied color over a pixel.
+    fn blend(&mut self, x: i32, y: i32, color: [u8; 4]) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+
+        let alpha = u32::from(color[3]);
+        if alpha == 0 {
+            return;
+        }
+
+        let start = y as usize * self.stride + x as usize * 4;
+        let pixel = &mut self.pixels[start..start + 4];
+        for (dst, src) in pixel.iter_mut().zip(color) {
+            *dst = (u32::from(src) + mul_div_255(u32::from(*dst), 255 - alpha)).min(255) as u8;
+        }
+    }
+
+    /// Blend a color over a pixel with a separate coverage for each channel.
+    fn blend_subpixel(&mut self, x: i32, y: i32, color: ct::Color, coverage: [u8; 4]) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+
+        let src = [color.r(), color.g(), color.b(), color.a()];
+        let start = y as usize * self.stride + x as usize * 4;
+        let pixel = &mut self.pixels[start..start + 4];
+
+        // The alpha channel of the coverage is the largest of the three.
+        for (i, dst) in pixel.iter_mut().enumerate() {
+            let alpha = mul_div_255(u32::from(coverage[i]), u32::from(color.a()));
+            let src = if i == 3 {
+                alpha
+            } else {
+                mul_div_255(u32::from(src[i]), alpha)
+            };
+            *dst = (src + mul_div_255(u32::from(*dst), 255 - alpha)).min(255) as u8;
+        }
+    }
+
+    /// Draw a glyph image with its origin at the given pixel.
+    ///
+    /// Masks are filled with `color`. Color images have their opacity multiplied by the alpha of
+    /// `color`.
+    pub(crate) fn draw_image(&mut self, image: &SwashImage, x: i32, y: i32, color: ct::Color) {
+        let placement = image.placement;
+        let left = x + placement.left;
+        let top = y - placement.top;
+        let width = placement.width as usize;
+        let tint = tint(image, color);
+        let subpixel = matches!(image.content, Content::SubpixelMask);
+
+        for (i, texel) in texels(image).enumerate() {
+            let (px, py) = (left + (i % width) as i32, top + (i / width) as i32);
+            if subpixel {
+                self.blend_subpixel(px, py, color, texel);
+            } else {
+                self.blend(px, py, multiply(texel, tint));
+            }
+        }
+    }
+
+    /// Fill a rectangle, antialiasing its edges.
+    pub(crate) fn fill_rect(&mut self, rect: Rect, color: piet::Color) {
+        let rect = rect.abs();
+        let (r, g, b, a) = color.as_rgba8();
+
+        let x0 = rect.x0.floor().max(0.0) as i32;
+        let y0 = rect.y0.floor().max(0.0) as i32;
+        let x1 = rect.x1.ceil().min(self.width as f64) as i32;
+        let y1 = rect.y1.ceil().min(self.height as f64) as i32;
+
+        for y in y0..y1 {
+            let cover_y = overlap(rect.y0, rect.y1, y);
+            for x in x0..x1 {
+                let coverage = overlap(rect.x0, rect.x1, x) * cover_y;
+                let alpha = (f64::from(a) * coverage).round() as u32;
+                self.blend(x, y, premultiply([r, g, b], alpha));
+            }
+        }
+    }
+
+    /// Stroke a path with the given line width.
+    pub(crate) fn stroke_path(&mut self, path: &BezPath, width: f64, color: piet::Color) {
+        let commands = cvt_commands(path);
+        let (mask, placement) = Mask::new(&commands)
+            .style(Stroke::new(width as f32))
+            .render();
+        let (r, g, b, a) = color.as_rgba8();
+
+        let row = placement.width as usize;
+        for (i, coverage) in mask.into_iter().enumerate() {
+            let x = placement.left + (i % row) as i32;
+            let y = placement.top + (i / row) as i32;
+            let alpha = mul_div_255(u32::from(coverage), u32::from(a));
+            self.blend(x, y, premultiply([r, g, b], alpha));
+        }
+    }
+}
+
+/// Build the table for correcting coverage values with the gamma and contrast.
+///
+/// Returns `None` if coverage is left unchanged.
+fn coverage_table(options: &RenderOptions) -> Option<[u8; 256]> {
+    let mut gamma = options.gamma;
+    if gamma <= 0.0 || !gamma.is_finite() {
+        warn!("Ignoring invalid gamma {}", gamma);
+        gamma = 1.0;
+    }
+    let contrast = options.contrast.clamp(0.0, 1.0);
+
+    if gamma == 1.0 && contrast == 0.0 {
+        return None;
+    }
+
+    let mut table = [0; 256];
+    for (i, value) in table.iter_mut().enumerate() {
+        let coverage = (i as f32 / 255.0).powf(gamma.recip());
+
+        // Mix with a smoothstep curve, which is steeper around the middle.
+        let smooth = coverage * coverage * (3.0 - 2.0 * coverage);
+        let coverage = coverage + (smooth - coverage) * contrast;
+
+        *value = (coverage * 255.0).round() as u8;
+    }
+
+    Some(table)
+}
+
+/// Correct the coverage of a mask. Color images are left alone.
+fn correct_coverage(image: &mut SwashImage, table: &[u8; 256]) {
+    match image.content {
+        Content::Mask => image
+            .data
+            .iter_mut()
+            .for_each(|coverage| *coverage = table[usize::from(*coverage)]),
+        Content::SubpixelMask => image.data.chunks_exact_mut(4).for_each(|pixel| {
+            pixel[..3]
+                .iter_mut()
+                .for_each(|coverage| *coverage = table[usize::from(*coverage)])
+        }),
+        Content::Color => {}
+    }
+}
+
+/// Convert a path from `swash`, flipping it so that Y points down.
+fn cvt_path(path: impl PathData) -> BezPath {
+    let cvt_point = |point: Vector| Point::new(f64::from(point.x), -f64::from(point.y));
+    let mut bez = BezPath::new();
+
+    for command in path.commands() {
+        match command {
+            Command::MoveTo(p) => bez.move_to(cvt_point(p)),
+            Command::LineTo(p) => bez.line_to(cvt_point(p)),
+            Command::QuadTo(c, p) => bez.quad_to(cvt_point(c), cvt_point(p)),
+            Command::CurveTo(c1, c2, p) => bez.curve_to(cvt_point(c1), cvt_point(c2), cvt_point(p)),
+            Command::Close => bez.close_path(),
+        }
+    }
+
+    bez
+}
+
+/// Convert a path into commands for `zeno`, keeping Y pointing down.
+fn cvt_commands(path: &BezPath) -> Vec<Command> {
+    let cvt_point = |point: Point| Vector::new(point.x as f32, point.y as f32);
+
+    path.elements()
+        .iter()
+        .map(|element| match *element {
+            PathEl::MoveTo(p) => Command::MoveTo(cvt_point(p)),
+            PathEl::LineTo(p) => Command::LineTo(cvt_point(p)),
+            PathEl::QuadTo(c, p) => Command::QuadTo(cvt_point(c), cvt_point(p)),
+            PathEl::CurveTo(c1, c2, p) => {
+                Command::CurveTo(cvt_point(c1), cvt_point(c2), cvt_point(p))
+            }
+            PathEl::ClosePath => Command::Close,
+        })
+        .collect()
+}
+
+/// Get the pixels of a glyph image as premultiplied RGBA.
+///
+/// Masks become white pixels with the coverage as their alpha, so that every image can be drawn
+/// by multiplying its pixels with the color returned by [`tint`].
+///
+/// Subpixel masks are the exception: their pixels hold the coverage of each channel, with the
+/// largest of them as the alpha. They can't be drawn as premultiplied colors.
+pub(crate) fn texels(image: &SwashImage) -> impl Iterator<Item = [u8; 4]> + '_ {
+    // Color outlines are composited into premultiplied pixels, while bitmaps are stored as they
+    // are.
+    let premultiplied = matches!(image.source, Source::ColorOutline(_));
+
+    let (mask, color) = match image.content {
+        Content::Mask => (&image.data[..], &[][..]),
+        _ => (&[][..], &image.data[..]),
+    };
+
+    let mask = mask.iter().map(|&coverage| [coverage; 4]);
+    let content = image.content;
+    let color = color.chunks_exact(4).map(move |pixel| {
+        let texel = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        match content {
+            Content::SubpixelMask => {
+                let [r, g, b, _] = texel;
+                [r, g, b, r.max(g).max(b)]
+            }
+            _ if premultiplied => texel,
+            _ => premultiply([texel[0], texel[1], texel[2]], u32::from(texel[3])),
+        }
+    });
+
+    mask.chain(color)
+}
+
+/// Get the premultiplied color that the pixels of a glyph image are multiplied by.
+///
+/// Masks are drawn in the color of the text, while color images only take its opacity.
+pub(crate) fn tint(image: &SwashImage, color: ct::Color) -> [u8; 4] {
+    match image.content {
+        Content::Color => [color.a(); 4],
+        _ => premultiply([color.r(), color.g(), color.b()], u32::from(color.a())),
+    }
+}
+
+/// Multiply two premultiplied colors together.
+fn multiply(a: [u8; 4], b: [u8; 4]) -> [u8; 4] {
+    [0, 1, 2, 3].map(|i| mul_div_255(u32::from(a[i]), u32::from(b[i])) as u8)
+}
+
+/// Get how much of the pixel starting at `pixel` is covered by the span from `start` to `end`.
+fn overlap(start: f64, end: f64, pixel: i32) -> f64 {
+    let pixel = f64::from(pixel);
+    (end.min(pixel + 1.0) - start.max(pixel)).clamp(0.0, 1.0)
+}
+
+/// Premultiply a color by an alpha value.
+fn premultiply(rgb: [u8; 3], alpha: u32) -> [u8; 4] {
+    let [r, g, b] = rgb.map(|c| mul_div_255(u32::from(c), alpha) as u8);
+    [r, g, b, alpha as u8]
+}
+
+/// Multiply two 8-bit values, treating 255 as 1.
+fn mul_div_255(a: u32, b: u32) -> u32 {
+    let product = a * b + 128;
+    (product + (product >> 8)) >> 8
+}
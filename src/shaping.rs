@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Figuring out when the cheaper basic shaper gives the same results as the advanced one.
+//!
+//! Basic shaping maps every character straight to a glyph using the first font that matches.
+//! This is exact as long as the text doesn't need any script-specific processing, and none of
+//! the fonts used would substitute or reposition any of its glyphs.
+
+use crate::attributes::ExtendedAttrsTable;
+use crate::raster::Variations;
+
+use cosmic_text as ct;
+use ct::fontdb::ID as FontId;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use ttf_parser::gpos::PositioningSubtable;
+use ttf_parser::gsub::SubstitutionSubtable;
+use ttf_parser::opentype_layout::LayoutTable;
+use ttf_parser::{GlyphId, Tag};
+
+use unicode_script::{Script, UnicodeScript};
+
+/// Features that are applied to horizontal Latin text by default.
+///
+/// `rustybuzz` also enables features that only ever apply to combining marks and to fractions,
+/// but text containing those is never considered simple.
+const DEFAULT_FEATURES: &[&[u8; 4]] = &[
+    b"rvrn", b"ccmp", b"locl", b"rlig", b"calt", b"clig", b"liga", b"rclt", b"kern", b"curs",
+    b"dist", b"abvm", b"blwm",
+];
+
+/// Tables that `rustybuzz` uses to shape text outside of GSUB and GPOS.
+const SHAPING_TABLES: &[&[u8; 4]] = &[b"kern", b"morx", b"kerx", b"trak"];
+
+/// Tell whether this text can be shaped by mapping each character to a glyph.
+///
+/// This is the case for left-to-right alphabets without combining characters.
+pub(crate) fn is_simple(text: &str) -> bool {
+    text.chars().all(|c| {
+        // The general punctuation block contains bidi controls and joiners.
+        c < '\u{2000}'
+            && matches!(
+                c.script(),
+                Script::Common | Script::Latin | Script::Greek | Script::Cyrillic
+            )
+    })
+}
+
+/// Which glyphs the basic shaper gives the same results for as the advanced shaper.
+///
+/// Reading the layout tables of a font is expensive, so the results are kept for as long as the
+/// font system is.
+#[derive(Debug, Default)]
+pub(crate) struct ShapingCache {
+    /// The glyphs that have been checked in each font.
+    fonts: HashMap<FontId, FontGlyphs>,
+}
+
+/// The glyphs that have been checked in a font.
+#[derive(Debug)]
+struct FontGlyphs {
+    /// Whether the font has tables that make the advanced shaper process every glyph.
+    complex: bool,
+
+    /// Whether each glyph is left alone by the default features of the font.
+    exact: HashMap<u16, bool>,
+}
+
+impl ShapingCache {
+    /// Tell whether a glyph of a font is left alone by the advanced shaper.
+    fn is_exact(&mut self, font: &ct::Font, glyph_id: u16) -> bool {
+        // The advanced shaper would have fallen back to another font.
+        if glyph_id == 0 {
+            return false;
+        }
+
+        let face = font.rustybuzz();
+        let glyphs = self.fonts.entry(font.id()).or_insert_with(|| FontGlyphs {
+            complex: SHAPING_TABLES
+                .iter()
+                .any(|tag| face.raw_face().table(Tag::from_bytes(tag)).is_some()),
+            exact: HashMap::new(),
+        });
+        if glyphs.complex {
+            return false;
+        }
+
+        *glyphs.exact.entry(glyph_id).or_insert_with(|| {
+            let glyph = GlyphId(glyph_id);
+            let tables = face.tables();
+            !tables.gsub.map_or(false, |table| {
+                affects(table, glyph, |lookup, i| {
+                    lookup
+                        .subtables
+                        .get::<SubstitutionSubtable<'_>>(i)
+                        .map(|subtable| subtable.coverage())
+                })
+            }) && !tables.gpos.map_or(false, |table| {
+                affects(table, glyph, |lookup, i| {
+                    lookup
+                        .subtables
+                        .get::<PositioningSubtable<'_>>(i)
+                        .map(|subtable| subtable.coverage())
+                })
+            })
+        })
+    }
+}
+
+/// Tell whether the basic shaper would produce the same glyphs the advanced shaper would.
+///
+/// This is decided before shaping, by looking up every character in the font that the basic
+/// shaper would use for it.
+pub(crate) fn basic_is_exact(
+    paragraphs: &[(&str, ct::AttrsList)],
+    table: &ExtendedAttrsTable,
+    font_system: &mut ct::FontSystem,
+    cache: &mut ShapingCache,
+) -> bool {
+    let mut styles = HashSet::new();
+
+    for (text, attrs_list) in paragraphs {
+        let mut run: Option<(ct::Attrs<'_>, Arc<ct::Font>)> = None;
+
+        for (index, c) in text.char_indices() {
+            // Text with compatible attributes is shaped using the same font.
+            let attrs = attrs_list.get_span(index);
+            let font = match &run {
+                Some((run_attrs, font)) if run_attrs.compatible(&attrs) => font.clone(),
+                _ => match basic_font(font_system, attrs) {
+                    Some(font) => {
+                        run = Some((attrs, font.clone()));
+                        font
+                    }
+                    None => return false,
+                },
+            };
+
+            // The basic shaper only uses the default instances of variable fonts.
+            if styles.insert((font.id(), attrs.metadata))
+                && !Variations::new(font.as_swash(), table.variations(attrs.metadata)).is_default()
+            {
+                return false;
+            }
+
+            if !cache.is_exact(&font, font.as_swash().charmap().map(c)) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Get the font the basic shaper uses for text with these attributes.
+///
+/// This is the first matching face of the requested family. If there is none, the basic shaper
+/// falls back to other fonts, which isn't worth predicting.
+fn basic_font(font_system: &mut ct::FontSystem, attrs: ct::Attrs<'_>) -> Option<Arc<ct::Font>> {
+    let matches = font_system.get_font_matches(attrs);
+    let id = {
+        let db = font_system.db();
+        let family = db.family_name(&attrs.family);
+        matches.iter().copied().find(|&id| {
+            db.face(id).map_or(false, |face| {
+                face.families.iter().any(|(name, _)| name == family)
+            })
+        })?
+    };
+
+    font_system.get_font(id)
+}
+
+/// Tell whether any of the default features in a layout table may apply to a glyph.
+fn affects<'a>(
+    table: LayoutTable<'a>,
+    glyph: GlyphId,
+    coverage: impl Fn(
+        &ttf_parser::opentype_layout::Lookup<'a>,
+        u16,
+    ) -> Option<ttf_parser::opentype_layout::Coverage<'a>>,
+) -> bool {
+    table
+        .features
+        .into_iter()
+        .filter(|feature| {
+            DEFAULT_FEATURES
+                .iter()
+                .any(|tag| feature.tag == Tag::from_bytes(tag))
+        })
+        .flat_map(|feature| feature.lookup_indices)
+        .filter_map(|index| table.lookups.get(index))
+        .any(|lookup| {
+            (0..lookup.subtables.len()).any(|i| {
+                // Be conservative about subtables we can't read.
+                coverage(&lookup, i).map_or(true, |coverage| coverage.contains(glyph))
+            })
+        })
+}
@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Writing text layouts out as SVG documents.
+
+use crate::lines::{DecorationStyle, StyledLine};
+use crate::text_layout::{BackgroundRect, GlyphOutline};
+
+use piet::kurbo::{PathEl, Point, Rect};
+use piet::Color;
+
+use std::fmt::{self, Write};
+
+/// Write an SVG document containing backgrounds, glyph outlines and text decorations.
+///
+/// `bounds` becomes the view box of the document.
+pub(crate) fn write_svg(
+    outlines: &[GlyphOutline],
+    lines: &[StyledLine],
+    backgrounds: &[BackgroundRect],
+    bounds: Rect,
+    default_color: Color,
+) -> String {
+    let mut svg = String::new();
+    write_document(
+        &mut svg,
+        outlines,
+        lines,
+        backgrounds,
+        bounds,
+        default_color,
+    )
+    .expect("writing to a String cannot fail");
+    svg
+}
+
+fn write_document(
+    svg: &mut String,
+    outlines: &[GlyphOutline],
+    lines: &[StyledLine],
+    backgrounds: &[BackgroundRect],
+    bounds: Rect,
+    default_color: Color,
+) -> fmt::Result {
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="{} {} {} {}">"#,
+        Num(bounds.width()),
+        Num(bounds.height()),
+        Num(bounds.x0),
+        Num(bounds.y0),
+        Num(bounds.width()),
+        Num(bounds.height()),
+    )?;
+
+    for background in backgrounds {
+        write_rect(svg, background.rect, background.color)?;
+    }
+
+    for outline in outlines {
+        if outline.path.elements().is_empty() {
+            continue;
+        }
+
+        svg.push_str(r#"<path d=""#);
+        write_path(svg, outline.path.elements())?;
+        svg.push('"');
+        write_fill(svg, outline.color.unwrap_or(default_color))?;
+        svg.push_str("/>\n");
+    }
+
+    for line in lines {
+        if line.style == DecorationStyle::Wavy {
+            svg.push_str(r#"<path d=""#);
+            write_path(svg, line.wavy_path().elements())?;
+            svg.push('"');
+            write_stroke(svg, line.color, line.thickness)?;
+            svg.push_str("/>\n");
+        } else {
+            for rect in line.rects() {
+                write_rect(svg, rect, line.color)?;
+            }
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    Ok(())
+}
+
+/// Write a filled rectangle.
+fn write_rect(svg: &mut String, rect: Rect, color: Color) -> fmt::Result {
+    write!(
+        svg,
+        r#"<rect x="{}" y="{}" width="{}" height="{}""#,
+        Num(rect.x0),
+        Num(rect.y0),
+        Num(rect.width()),
+        Num(rect.height()),
+    )?;
+    write_fill(svg, color)?;
+    svg.push_str("/>\n");
+    Ok(())
+}
+
+/// Write the data of a path.
+fn write_path(svg: &mut String, elements: &[PathEl]) -> fmt::Result {
+    let point = |p: Point| format!("{} {}", Num(p.x), Num(p.y));
+
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            svg.push(' ');
+        }
+
+        match *element {
+            PathEl::MoveTo(p) => write!(svg, "M{}", point(p))?,
+            PathEl::LineTo(p) => write!(svg, "L{}", point(p))?,
+            PathEl::QuadTo(c, p) => write!(svg, "Q{} {}", point(c), point(p))?,
+            PathEl::CurveTo(c1, c2, p) => write!(svg, "C{} {} {}", point(c1), point(c2), point(p))?,
+            PathEl::ClosePath => svg.push('Z'),
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the fill attributes for a color.
+fn write_fill(svg: &mut String, color: Color) -> fmt::Result {
+    let (r, g, b, a) = color.as_rgba8();
+    write!(svg, r##" fill="#{:02x}{:02x}{:02x}""##, r, g, b)?;
+
+    if a != 255 {
+        write!(svg, r#" fill-opacity="{}""#, Num(f64::from(a) / 255.0))?;
+    }
+
+    Ok(())
+}
+
+/// Write the stroke attributes for a color and line width, without any fill.
+fn write_stroke(svg: &mut String, color: Color, width: f64) -> fmt::Result {
+    let (r, g, b, a) = color.as_rgba8();
+    write!(
+        svg,
+        r##" fill="none" stroke="#{:02x}{:02x}{:02x}" stroke-width="{}""##,
+        r,
+        g,
+        b,
+        Num(width)
+    )?;
+
+    if a != 255 {
+        write!(svg, r#" stroke-opacity="{}""#, Num(f64::from(a) / 255.0))?;
+    }
+
+    Ok(())
+}
+
+/// A number, written with at most two decimal places.
+struct Num(f64);
+
+impl fmt::Display for Num {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rounded = (self.0 * 100.0).round() / 100.0;
+
+        // Avoid writing "-0".
+        if rounded == 0.0 {
+            return f.write_str("0");
+        }
+
+        let text = format!("{:.2}", rounded);
+        f.write_str(text.trim_end_matches('0').trim_end_matches('.'))
+    }
+}
@@ -19,9 +19,11 @@
 //! The `Text` API, the root of the system.
 
 use crate::export_work::ExportWork;
+use crate::raster::Rasterizer;
+use crate::shaping::ShapingCache;
 use crate::text_layout::{InkRectangleState, TextLayout};
 use crate::text_layout_builder::TextLayoutBuilder;
-use crate::{channel, FontError, STANDARD_DPI};
+use crate::{channel, Antialiasing, FontError, LineHeight, RenderOptions, STANDARD_DPI};
 
 #[cfg(feature = "embed_fonts")]
 use crate::embedded_fonts;
@@ -73,8 +75,12 @@ impl fmt::Debug for Text {
             Err(_) => ds.field("font_db", &Borrowed),
         };
 
-        // Finish with the DPI.
-        ds.field("dpi", &self.0.dpi.get()).finish()
+        // Finish with the rendering options.
+        ds.field("dpi", &self.0.dpi.get())
+            .field("line_height", &self.0.line_height.get())
+            .field("antialiasing", &self.0.antialiasing.get())
+            .field("render_options", &self.0.render_options.get())
+            .finish()
     }
 }
 
@@ -94,8 +100,20 @@ struct Inner {
     /// The current dots-per-inch (DPI) of the rendering surface.
     dpi: Cell<f64>,
 
+    /// The default line height for new layouts.
+    line_height: Cell<LineHeight>,
+
+    /// How glyphs are antialiased when they are rasterized.
+    antialiasing: Cell<Antialiasing>,
+
+    /// How glyphs are rasterized.
+    render_options: Cell<RenderOptions>,
+
     /// Cache the ink rectangle calculation state.
     ink: RefCell<InkRectangleState>,
+
+    /// Cache the rendered glyphs.
+    raster: RefCell<Rasterizer>,
 }
 
 impl Inner {
@@ -210,6 +228,9 @@ pub(crate) struct FontSystemAndDefaults {
     /// This contains the default serif, sans-serif and monospace fonts, as well as
     /// any fonts embedded into the executable.
     pub(crate) default_fonts: Vec<FontId>,
+
+    /// Which glyphs of each font can be shaped with the basic shaper.
+    pub(crate) shaping: ShapingCache,
 }
 
 impl FontSystemAndDefaults {
@@ -218,7 +239,29 @@ impl FontSystemAndDefaults {
         let mut owned = AttrsOwned::new(attrs);
         let original = attrs;
 
-        // If we have a font, great!
+        // If the family has a face with this style, great!
+        if self.family_matches(attrs) {
+            return owned;
+        }
+
+        // If the family lacks the style, use one of its other faces. The glyphs are emboldened
+        // and slanted when they are rendered instead, as the metadata still has the style.
+        // Variable fonts are set to the requested weight instead.
+        for (style, weight) in [
+            (ct::Style::Normal, attrs.weight),
+            (attrs.style, ct::Weight::NORMAL),
+            (ct::Style::Normal, ct::Weight::NORMAL),
+        ] {
+            owned.style = style;
+            owned.weight = weight;
+            if self.family_matches(owned.as_attrs()) {
+                return owned;
+            }
+        }
+        owned.style = attrs.style;
+        owned.weight = attrs.weight;
+
+        // If another family has a face with this style, `cosmic-text` falls back to it.
         if !self.system.get_font_matches(attrs).is_empty() {
             return owned;
         }
@@ -245,6 +288,19 @@ impl FontSystemAndDefaults {
         warn!("no fonts match attributes: {:?}", original);
         AttrsOwned::new(original)
     }
+
+    /// Tell whether a face of the requested family matches the attributes.
+    fn family_matches(&mut self, attrs: Attrs<'_>) -> bool {
+        let matches = self.system.get_font_matches(attrs);
+        let db = self.system.db();
+        let family = db.family_name(&attrs.family);
+
+        matches.iter().any(|&id| {
+            db.face(id).map_or(false, |face| {
+                face.families.iter().any(|(name, _)| name == family)
+            })
+        })
+    }
 }
 
 impl Text {
@@ -258,6 +314,10 @@ impl Text {
         self.0.ink.borrow_mut()
     }
 
+    pub(crate) fn borrow_raster(&self) -> RefMut<'_, Rasterizer> {
+        self.0.raster.borrow_mut()
+    }
+
     /// Take the inner `BufferLine` buffer.
     pub(crate) fn take_buffer(&self) -> Vec<BufferLine> {
         self.0.buffer.replace(Vec::new())
@@ -322,12 +382,33 @@ impl Text {
             send.send(FontSystemAndDefaults {
                 system: fs,
                 default_fonts: defaults,
+                shaping: ShapingCache::default(),
             });
         });
 
         Self::with_delayed_font_system(DelayedFontSystem::Waiting(recv))
     }
 
+    /// Create a new `Text` renderer that only uses the fonts embedded into this crate.
+    ///
+    /// No system fonts are loaded, and the generic font families map to the embedded DejaVu
+    /// fonts. This makes layouts come out the same on every machine, which is useful for tests
+    /// and snapshots.
+    #[cfg(feature = "embed_fonts")]
+    pub fn with_embedded_fonts() -> Self {
+        let mut db = ct::fontdb::Database::new();
+        db.set_sans_serif_family("DejaVu Sans");
+        db.set_serif_family("DejaVu Serif");
+        db.set_monospace_family("DejaVu Sans Mono");
+
+        let mut fs = FontSystem::new_with_locale_and_db("en-US".into(), db);
+        if let Err(_err) = embedded_fonts::load_embedded_font_data(&mut fs) {
+            error!("failed to load embedded font data: {}", _err);
+        }
+
+        Self::from_font_system(fs)
+    }
+
     /// Create a new `Text` renderer from an existing `FontSystem`.
     pub fn from_font_system(font_system: FontSystem) -> Self {
         let defaults = {
@@ -348,6 +429,7 @@ impl Text {
         Self::with_delayed_font_system(DelayedFontSystem::Real(FontSystemAndDefaults {
             system: font_system,
             default_fonts: defaults,
+            shaping: ShapingCache::default(),
         }))
     }
 
@@ -357,7 +439,11 @@ impl Text {
             font_db_free: Event::new(),
             buffer: Cell::new(Vec::new()),
             dpi: Cell::new(STANDARD_DPI),
+            line_height: Cell::new(LineHeight::default()),
+            antialiasing: Cell::new(Antialiasing::default()),
+            render_options: Cell::new(RenderOptions::default()),
             ink: RefCell::new(InkRectangleState::new()),
+            raster: RefCell::new(Rasterizer::new()),
         }))
     }
 
@@ -373,6 +459,58 @@ impl Text {
         self.0.dpi.replace(dpi)
     }
 
+    /// Get the default line height for new layouts.
+    pub fn line_height(&self) -> LineHeight {
+        self.0.line_height.get()
+    }
+
+    /// Set the default line height for new layouts.
+    ///
+    /// By default, the line height is equal to the largest font size in the line. This can be
+    /// overridden for a single layout using [`TextLayoutBuilder::line_height`].
+    ///
+    /// Returns the old line height.
+    ///
+    /// [`TextLayoutBuilder::line_height`]: crate::TextLayoutBuilder::line_height
+    pub fn set_line_height(&self, line_height: LineHeight) -> LineHeight {
+        self.0.line_height.replace(line_height)
+    }
+
+    /// Get how glyphs are antialiased when they are rasterized.
+    pub fn antialiasing(&self) -> Antialiasing {
+        self.0.antialiasing.get()
+    }
+
+    /// Set how glyphs are antialiased when they are rasterized.
+    ///
+    /// This applies to [`TextLayout::draw_into`] and to [`GlyphAtlas`]. By default, glyphs are
+    /// antialiased in grayscale. Subpixel antialiasing makes text look sharper on LCD screens,
+    /// but it can only be blended onto opaque backgrounds and needs the order of the subpixels
+    /// of the screen.
+    ///
+    /// Returns the old antialiasing mode.
+    ///
+    /// [`TextLayout::draw_into`]: crate::TextLayout::draw_into
+    /// [`GlyphAtlas`]: crate::GlyphAtlas
+    pub fn set_antialiasing(&self, antialiasing: Antialiasing) -> Antialiasing {
+        self.0.antialiasing.replace(antialiasing)
+    }
+
+    /// Get the options for rasterizing glyphs.
+    pub fn render_options(&self) -> RenderOptions {
+        self.0.render_options.get()
+    }
+
+    /// Set the options for rasterizing glyphs.
+    ///
+    /// Layouts that were already built keep the ink rectangle they were built with, but are
+    /// drawn using the new options.
+    ///
+    /// Returns the old options.
+    pub fn set_render_options(&self, options: RenderOptions) -> RenderOptions {
+        self.0.render_options.replace(options)
+    }
+
     /// Tell if the font system is loaded.
     pub fn is_loaded(&self) -> bool {
         self.0
@@ -432,6 +570,110 @@ impl Text {
         let mut font_db = self.0.borrow_font_system()?;
         font_db.get().map(|fs| f(&mut fs.system))
     }
+
+    /// Load a font or a font collection, returning every face that was loaded.
+    ///
+    /// [`piet::Text::load_font`] only returns a single family. This method can be used to see
+    /// every face that was added to the [`FontSystem`], which is useful when the data is a
+    /// font collection (TTC/OTC) containing several faces or even several families.
+    pub fn load_font_collection(&self, data: &[u8]) -> Result<Vec<LoadedFace>, Error> {
+        let span = warn_span!("load_font_collection", data_len = data.len());
+        let _enter = span.enter();
+
+        let mut db_guard = self
+            .0
+            .borrow_font_system()
+            .ok_or_else(|| Error::BackendError(FontError::AlreadyBorrowed.into()))?;
+        let db = db_guard
+            .get()
+            .ok_or_else(|| Error::BackendError(FontError::NotLoaded.into()))?;
+
+        load_font_collection(db, data)
+    }
+}
+
+/// A font face that was loaded by [`Text::load_font_collection`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedFace {
+    /// The family that the face belongs to.
+    family: FontFamily,
+
+    /// The ID of the face in the font database.
+    id: FontId,
+}
+
+impl LoadedFace {
+    /// Get the family that this face belongs to.
+    ///
+    /// This can be passed to [`TextLayoutBuilder::font`](piet::TextLayoutBuilder::font) to use
+    /// the face.
+    pub fn family(&self) -> &FontFamily {
+        &self.family
+    }
+
+    /// Get the ID of this face in the font database.
+    pub fn id(&self) -> FontId {
+        self.id
+    }
+}
+
+/// Load every face in the font data into the font system.
+fn load_font_collection(
+    db: &mut FontSystemAndDefaults,
+    data: &[u8],
+) -> Result<Vec<LoadedFace>, Error> {
+    // Insert the data source into the underlying font database.
+    let ids = db
+        .system
+        .db_mut()
+        .load_font_source(ct::fontdb::Source::Binary(Arc::new(data.to_vec())));
+
+    if ids.is_empty() {
+        error!("font collection contained no fonts");
+        return Err(Error::FontLoadingFailed);
+    }
+
+    // Resolve the family name of every face.
+    //
+    // Faces in a collection usually share a family, but they may list its name in a different
+    // order (e.g. localized names first). Prefer a name that an earlier face already resolved
+    // to, so that the whole family is reported under a single name.
+    let mut faces = Vec::with_capacity(ids.len());
+    for id in ids {
+        let face = match db.system.db().face(id) {
+            Some(face) => face,
+            None => {
+                warn!("font face {:?} disappeared after loading", id);
+                continue;
+            }
+        };
+
+        let known = face.families.iter().find(|(name, _)| {
+            faces
+                .iter()
+                .any(|loaded: &LoadedFace| loaded.family.name() == name)
+        });
+        let name = match known.or_else(|| face.families.first()) {
+            Some((name, _)) => name,
+            None => {
+                warn!("font face {:?} has no family name", id);
+                continue;
+            }
+        };
+
+        trace!("loaded font face {:?} in family {}", id, name);
+        faces.push(LoadedFace {
+            family: FontFamily::new_unchecked(name.as_str()),
+            id,
+        });
+    }
+
+    if faces.is_empty() {
+        error!("no faces in the font collection could be loaded");
+        return Err(Error::FontLoadingFailed);
+    }
+
+    Ok(faces)
 }
 
 impl Default for Text {
@@ -477,45 +719,9 @@ impl piet::Text for Text {
     }
 
     fn load_font(&mut self, data: &[u8]) -> Result<FontFamily, Error> {
-        let span = warn_span!("load_font", data_len = data.len());
-        let _enter = span.enter();
-
-        let mut db_guard = self
-            .0
-            .borrow_font_system()
-            .ok_or_else(|| Error::BackendError(FontError::AlreadyBorrowed.into()))?;
-        let db = db_guard
-            .get()
-            .ok_or_else(|| Error::BackendError(FontError::NotLoaded.into()))?;
-
-        // Insert the data source into the underlying font database.
-        let id = {
-            let ids = db
-                .system
-                .db_mut()
-                .load_font_source(ct::fontdb::Source::Binary(Arc::new(data.to_vec())));
-
-            // For simplicity, just take the first ID if this is a font collection.
-            match ids.len() {
-                0 => {
-                    error!("font collection contained no fonts");
-                    return Err(Error::FontLoadingFailed);
-                }
-                1 => ids[0],
-                _len => {
-                    warn!("received font collection of length {_len}, only selecting first font");
-                    ids[0]
-                }
-            }
-        };
-
-        // Get the font back.
-        let font = db
-            .system
-            .db()
-            .face(id)
-            .ok_or_else(|| Error::FontLoadingFailed)?;
-        Ok(FontFamily::new_unchecked(font.families[0].0.as_str()))
+        // All of the faces are registered, but piet only has room for one family.
+        let faces = Text::load_font_collection(self, data)?;
+        Ok(faces[0].family.clone())
     }
 
     fn new_text_layout(&mut self, text: impl TextStorage) -> Self::TextLayoutBuilder {
@@ -16,24 +16,36 @@
 // You should have received a copy of the GNU Lesser General Public License and the Mozilla
 // Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::attributes::ExtendedAttrsTable;
+use crate::layout::Line;
+use crate::lines::{
+    DecorationMetrics, DecorationStyle, LineProcessor, StyledLine, TYPICAL_ASCENT, TYPICAL_DESCENT,
+};
+use crate::raster::{Canvas, Rasterizer, Synthesis, Variations};
+use crate::svg;
 use crate::text::Text;
+use crate::text_layout_builder::ShapingMode;
+use crate::{cvt_color, Antialiasing, FontError, RenderOptions};
 
 use cosmic_text as ct;
-use ct::{Buffer, LayoutRunIter};
+use ct::{Buffer, LayoutRun};
 
-use piet::kurbo::{Point, Rect, Size, Vec2};
-use piet::TextStorage;
+use piet::kurbo::{Affine, BezPath, Point, Rect, Size, Vec2};
+use piet::{util, TextStorage};
 
 use swash::scale::image::Image as SwashImage;
 use swash::scale::outline::Outline as SwashOutline;
 use swash::scale::{ScaleContext, StrikeWith};
 use swash::zeno;
 
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+
 use std::cell::Cell;
-use std::cmp;
 use std::collections::hash_map::{Entry, HashMap};
 use std::fmt;
+use std::ops::{Range, RangeBounds};
 use std::rc::Rc;
+use std::slice;
 
 /// A text layout.
 #[derive(Clone)]
@@ -61,9 +73,21 @@ struct BufferWrapper {
     /// The original buffer.
     buffer: Option<Buffer>,
 
+    /// The lines of text, laid out.
+    lines: Vec<Line>,
+
+    /// The attributes referred to by the metadata of the glyphs.
+    attrs: ExtendedAttrsTable,
+
     /// Run metrics.
     run_metrics: Vec<piet::LineMetric>,
 
+    /// The ranges of text that were cut off.
+    truncated_ranges: Vec<Range<usize>>,
+
+    /// The shaper that was used for the text.
+    shaping: ct::Shaping,
+
     /// Ink rectangle for the buffer.
     ink_rectangle: Rect,
 
@@ -97,29 +121,71 @@ impl Drop for BufferWrapper {
 
 impl TextLayout {
     /// Create a new `TextLayout`.
+    ///
+    /// `lines` are the lines that `buffer` was laid out into, and `paragraph_offsets` contains
+    /// the offset of each of the buffer's lines into `string`. `attrs` holds the attributes that
+    /// the glyphs' metadata refers to, and `shaping` is the shaper that was used to shape
+    /// `buffer`.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         text: Text,
         buffer: Buffer,
         string: Box<dyn TextStorage>,
         glyph_size: i32,
         font_system: &mut ct::FontSystem,
+        lines: Vec<Line>,
+        attrs: ExtendedAttrsTable,
+        paragraph_offsets: &[usize],
+        shaping: ct::Shaping,
     ) -> Self {
         let span = trace_span!("TextLayout::new", string = %string.as_str());
         let _guard = span.enter();
 
         // Figure out the metrics.
-        let run_metrics = buffer
-            .layout_runs()
-            .map(|run| RunMetrics::new(run, glyph_size as f64))
+        let run_metrics = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                // The last line of a paragraph also covers the paragraph separator, and the last
+                // line of the layout covers any text that was cut off after it.
+                let offset = paragraph_offsets[line.paragraph];
+                let end_offset = match (lines.get(i + 1), paragraph_offsets.get(line.paragraph + 1))
+                {
+                    (None, _) => string.len(),
+                    (Some(next), Some(&next_offset)) if next.paragraph != line.paragraph => {
+                        next_offset
+                    }
+                    _ => offset + line.range.end,
+                };
+
+                RunMetrics::new(line, offset, end_offset)
+            })
             .map(|RunMetrics { line_metric }| line_metric)
+            .collect::<Vec<_>>();
+
+        // Figure out which text was cut off.
+        let truncated_ranges = lines
+            .iter()
+            .zip(&run_metrics)
+            .filter_map(|(line, metric)| {
+                let offset = paragraph_offsets[line.paragraph];
+                let end = if metric.end_offset == string.len() {
+                    string.len()
+                } else {
+                    offset + line.range.end
+                };
+
+                line.truncated.map(|start| offset + start..end)
+            })
             .collect();
 
         // Scale up the buffers to get a good idea of the ink rectangle.
         let mut ink_context = text.borrow_ink();
+        let options = text.render_options();
+        ink_context.set_hinting(options.hinting);
         let mut missing_bbox_count = 0;
 
-        let bounding_boxes = buffer
-            .layout_runs()
+        let bounding_boxes = LayoutRunIter::new(&buffer, &lines)
             .flat_map(|run| {
                 let run_y = run.line_y;
                 run.glyphs.iter().map(move |glyph| (glyph, run_y))
@@ -132,7 +198,8 @@ impl TextLayout {
                 );
 
                 // Figure out the bounding box.
-                match ink_context.bounding_box(&physical, font_system) {
+                let synthesis = Synthesis::for_glyph(glyph, &options, &attrs, font_system);
+                match ink_context.bounding_box(&physical, synthesis, font_system) {
                     Some(mut rect) => {
                         rect = rect + offset;
                         Some(rect)
@@ -157,7 +224,11 @@ impl TextLayout {
                 string,
                 glyph_size,
                 buffer: Some(buffer),
+                lines,
+                attrs,
                 run_metrics,
+                truncated_ranges,
+                shaping,
                 handle: text,
                 ink_rectangle,
                 logical_size: Cell::new(None),
@@ -166,36 +237,758 @@ impl TextLayout {
     }
 
     /// Get a reference to the inner `Buffer`.
+    ///
+    /// The buffer's own layout uses the default font size for all of the text. In order to
+    /// account for ranges with different font sizes, use [`layout_runs`] instead of
+    /// [`Buffer::layout_runs`].
+    ///
+    /// [`layout_runs`]: Self::layout_runs
     pub fn buffer(&self) -> &Buffer {
         self.text_buffer.buffer()
     }
 
     /// Get an iterator over the layout runs.
     pub fn layout_runs(&self) -> LayoutRunIter<'_> {
-        self.buffer().layout_runs()
+        LayoutRunIter::new(self.buffer(), &self.text_buffer.lines)
+    }
+
+    /// Draw this layout into a buffer of pixels.
+    ///
+    /// `pixels` contains premultiplied RGBA pixels with 8 bits per channel, in rows that are
+    /// `stride` bytes apart. The layout is drawn with its top-left corner at `origin`, blending
+    /// glyphs, color glyphs, underlines and strikethroughs over the existing pixels. Text without
+    /// a color of its own is drawn using `default_color`.
+    ///
+    /// Glyphs are antialiased as set by [`Text::set_antialiasing`], and rendered using the
+    /// [`RenderOptions`] of the [`Text`].
+    ///
+    /// [`RenderOptions`]: crate::RenderOptions
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the font system is already borrowed or hasn't loaded yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stride` is less than four bytes per pixel, or if `pixels` is too small to hold
+    /// `height` rows.
+    pub fn draw_into(
+        &self,
+        pixels: &mut [u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        origin: impl Into<Point>,
+        default_color: piet::Color,
+    ) -> Result<(), piet::Error> {
+        let origin = origin.into();
+        let mut canvas = Canvas::new(pixels, width, height, stride);
+        let lines = self.decoration_lines(default_color)?;
+        let default_color = cvt_color(default_color);
+
+        // Paint the backgrounds beneath the glyphs.
+        for background in self.background_rects() {
+            canvas.fill_rect(background.rect + origin.to_vec2(), background.color);
+        }
+
+        self.with_rasterizer(|raster, system| {
+            for run in self.layout_runs() {
+                for glyph in run.glyphs {
+                    let physical =
+                        glyph.physical((origin.x as f32, origin.y as f32 + run.line_y), 1.0);
+
+                    let synthesis = raster.synthesis(glyph, &self.text_buffer.attrs, system);
+                    if let Some(image) = raster.image(physical.cache_key, synthesis, system) {
+                        let color = glyph.color_opt.unwrap_or(default_color);
+                        canvas.draw_image(image, physical.x, physical.y, color);
+                    }
+                }
+            }
+        })?;
+
+        // Draw the decoration lines on top of the glyphs.
+        for line in lines {
+            if line.style == DecorationStyle::Wavy {
+                let path = Affine::translate(origin.to_vec2()) * line.wavy_path();
+                canvas.stroke_path(&path, line.thickness, line.color);
+            } else {
+                for rect in line.rects() {
+                    canvas.fill_rect(rect + origin.to_vec2(), line.color);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the outlines of the glyphs in this layout.
+    ///
+    /// The outlines are positioned in layout coordinates and are not hinted, which makes them
+    /// suitable for drawing text as paths in vector formats. Color glyphs have an outline for
+    /// each of their layers, in the order they should be drawn.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the font system is already borrowed or hasn't loaded yet.
+    pub fn glyph_outlines(&self) -> Result<Vec<GlyphOutline>, piet::Error> {
+        self.with_rasterizer(|raster, system| {
+            let mut outlines = vec![];
+
+            for (line_number, run) in self.layout_runs().enumerate() {
+                let offset = self.paragraph_offset(line_number);
+
+                for glyph in run.glyphs {
+                    let origin = Vec2::new(
+                        f64::from(glyph.x + glyph.font_size * glyph.x_offset),
+                        f64::from(run.line_y + glyph.y - glyph.font_size * glyph.y_offset),
+                    );
+                    let synthesis = raster.synthesis(glyph, &self.text_buffer.attrs, system);
+                    let layers = raster.outline(
+                        glyph.font_id,
+                        glyph.glyph_id,
+                        glyph.font_size,
+                        synthesis,
+                        system,
+                    );
+
+                    outlines.extend(layers.iter().map(|layer| {
+                        let mut path = layer.path.clone();
+                        path.apply_affine(Affine::translate(origin));
+
+                        GlyphOutline {
+                            path,
+                            color: layer.color.or_else(|| {
+                                glyph
+                                    .color_opt
+                                    .map(|c| piet::Color::rgba8(c.r(), c.g(), c.b(), c.a()))
+                            }),
+                            range: offset + glyph.start..offset + glyph.end,
+                        }
+                    }));
+                }
+            }
+
+            outlines
+        })
+    }
+
+    /// Write this layout out as an SVG document.
+    ///
+    /// Backgrounds are written as `<rect>` elements beneath the glyphs, which are written as
+    /// `<path>` elements. Underlines, overlines and strikethroughs come last, as `<rect>` elements,
+    /// except for wavy lines, which are stroked `<path>` elements. Text without a color of its
+    /// own is filled with `default_color`. The view box covers the layout, its ink and its
+    /// decorations, in layout coordinates.
+    ///
+    /// The output only depends on the layout and the fonts used to make it. Use
+    /// [`Text::with_embedded_fonts`] to get the same document on every machine.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the font system is already borrowed or hasn't loaded yet.
+    pub fn to_svg(&self, default_color: piet::Color) -> Result<String, piet::Error> {
+        let outlines = self.glyph_outlines()?;
+
+        let lines = self.decoration_lines(default_color)?;
+        let backgrounds = self.background_rects();
+
+        let mut bounds = Rect::from_origin_size(Point::ZERO, piet::TextLayout::size(self));
+        let ink = piet::TextLayout::image_bounds(self);
+        if ink.area() > 0.0 {
+            bounds = bounds.union(ink);
+        }
+        for line in &lines {
+            bounds = bounds.union(line.bounds());
+        }
+        for background in &backgrounds {
+            bounds = bounds.union(background.rect);
+        }
+
+        Ok(svg::write_svg(
+            &outlines,
+            &lines,
+            &backgrounds,
+            bounds.expand(),
+            default_color,
+        ))
+    }
+
+    /// Get the rectangles to paint behind text that has a background color.
+    ///
+    /// Each rectangle covers the full height of its line, and adjacent glyphs with the same color
+    /// share a rectangle. The rectangles should be painted before the glyphs.
+    pub fn background_rects(&self) -> Vec<BackgroundRect> {
+        let table = &self.text_buffer.attrs;
+        let mut rects: Vec<BackgroundRect> = vec![];
+
+        for (line_number, (line, metric)) in self
+            .text_buffer
+            .lines
+            .iter()
+            .zip(&self.text_buffer.run_metrics)
+            .enumerate()
+        {
+            for glyph in &line.glyphs {
+                let color = match table.get(glyph.metadata).background {
+                    Some(color) => color,
+                    None => continue,
+                };
+                let (x0, x1) = (glyph.x, glyph.x + glyph.w);
+
+                // Merge with the previous rectangle if they touch and have the same color.
+                if let Some(last) = rects.last_mut() {
+                    if last.line == line_number
+                        && last.color == color
+                        && (last.rect.x1 - f64::from(x0)).abs() < 0.01
+                    {
+                        last.rect.x1 = x1.into();
+                        continue;
+                    }
+                }
+
+                rects.push(BackgroundRect {
+                    rect: Rect::new(
+                        x0.into(),
+                        metric.y_offset,
+                        x1.into(),
+                        metric.y_offset + metric.height,
+                    ),
+                    color,
+                    line: line_number,
+                });
+            }
+        }
+
+        rects
+    }
+
+    /// Get the underlines, overlines and strikethroughs of this layout.
+    ///
+    /// Lines are placed using the metrics of the font of each glyph. They use the colors set with
+    /// [`TextLayoutBuilder::range_decoration_color`] and
+    /// [`TextLayoutBuilder::range_decoration_line_color`], falling back to the color of the text
+    /// and then to `default_color`.
+    ///
+    /// [`TextLayoutBuilder::range_decoration_color`]: crate::TextLayoutBuilder::range_decoration_color
+    /// [`TextLayoutBuilder::range_decoration_line_color`]: crate::TextLayoutBuilder::range_decoration_line_color
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the font system is already borrowed or hasn't loaded yet.
+    pub fn decoration_lines(
+        &self,
+        default_color: piet::Color,
+    ) -> Result<Vec<StyledLine>, piet::Error> {
+        let table = &self.text_buffer.attrs;
+        let mut lines = LineProcessor::new();
+        let mut font_metrics = HashMap::new();
+
+        self.with_rasterizer(|_, system| {
+            for run in self.layout_runs() {
+                for glyph in run.glyphs {
+                    let attrs = table.get(glyph.metadata);
+                    let text_color = match glyph.color_opt {
+                        Some(color) => {
+                            piet::Color::rgba8(color.r(), color.g(), color.b(), color.a())
+                        }
+                        None => default_color,
+                    };
+                    let color = |line| attrs.decoration_color(line).unwrap_or(text_color);
+
+                    // Variable fonts have different metrics for each instance.
+                    let metrics = font_metrics
+                        .entry((glyph.font_id, glyph.metadata))
+                        .or_insert_with(|| {
+                            system.get_font(glyph.font_id).map(|font| {
+                                let font = font.as_swash();
+                                let variations =
+                                    Variations::new(font, table.variations(glyph.metadata));
+                                font.metrics(variations.coords())
+                            })
+                        });
+                    let metrics = match metrics {
+                        Some(metrics) => DecorationMetrics::from_swash(*metrics, glyph.font_size),
+                        None => DecorationMetrics::fallback(
+                            TYPICAL_ASCENT,
+                            TYPICAL_DESCENT,
+                            glyph.font_size,
+                        ),
+                    };
+
+                    lines.handle_styled_glyph(glyph, run.line_y, color, metrics);
+                }
+            }
+        })?;
+
+        Ok(lines.lines())
+    }
+
+    /// Get how the glyphs of this layout are rendered.
+    pub(crate) fn render_options(&self) -> (Antialiasing, RenderOptions) {
+        let handle = &self.text_buffer.handle;
+        (handle.antialiasing(), handle.render_options())
+    }
+
+    /// Get the attributes referred to by the metadata of the glyphs.
+    pub(crate) fn attrs_table(&self) -> &ExtendedAttrsTable {
+        &self.text_buffer.attrs
+    }
+
+    /// Run a closure with the glyph rasterizer and the font system.
+    pub(crate) fn with_rasterizer<R>(
+        &self,
+        f: impl FnOnce(&mut Rasterizer, &mut ct::FontSystem) -> R,
+    ) -> Result<R, piet::Error> {
+        let handle = &self.text_buffer.handle;
+        let mut font_system_guard = handle
+            .borrow_font_system()
+            .ok_or(piet::Error::BackendError(FontError::AlreadyBorrowed.into()))?;
+        let font_system = font_system_guard
+            .get()
+            .ok_or(piet::Error::BackendError(FontError::NotLoaded.into()))?;
+
+        let mut raster = handle.borrow_raster();
+        raster.configure(handle.antialiasing(), handle.render_options());
+        Ok(f(&mut raster, &mut font_system.system))
+    }
+
+    /// Get the ranges of text that were cut off.
+    ///
+    /// Text is cut off when [`TextLayoutBuilder::max_lines`] is exceeded, or when a line is
+    /// truncated because of [`TextOverflow::Ellipsis`]. The ranges are in the same order as the
+    /// lines they were cut off from. [`text`] still returns all of the text.
+    ///
+    /// [`TextLayoutBuilder::max_lines`]: crate::TextLayoutBuilder::max_lines
+    /// [`TextOverflow::Ellipsis`]: crate::TextOverflow::Ellipsis
+    /// [`text`]: piet::TextLayout::text
+    pub fn truncated_ranges(&self) -> &[Range<usize>] {
+        &self.text_buffer.truncated_ranges
+    }
+
+    /// Tell whether any text was cut off.
+    pub fn is_truncated(&self) -> bool {
+        !self.text_buffer.truncated_ranges.is_empty()
+    }
+
+    /// Get the shaper that was used for this layout.
+    ///
+    /// This is never [`ShapingMode::Automatic`]; it is either [`ShapingMode::Basic`] or
+    /// [`ShapingMode::Advanced`], depending on what was picked for the text.
+    pub fn shaping_mode(&self) -> ShapingMode {
+        match self.text_buffer.shaping {
+            ct::Shaping::Basic => ShapingMode::Basic,
+            ct::Shaping::Advanced => ShapingMode::Advanced,
+        }
+    }
+
+    /// Get the index of the line containing a text position.
+    ///
+    /// Positions past the end of the text belong to the last line.
+    fn line_for_index(&self, idx: usize) -> Option<usize> {
+        let metrics = &self.text_buffer.run_metrics;
+        metrics
+            .iter()
+            .position(|metric| idx < metric.end_offset)
+            .or_else(|| metrics.len().checked_sub(1))
+    }
+
+    /// Get the offset of the paragraph that a line belongs to.
+    fn paragraph_offset(&self, line_number: usize) -> usize {
+        let metric = &self.text_buffer.run_metrics[line_number];
+        let line = &self.text_buffer.lines[line_number];
+        metric.start_offset - line.range.start
+    }
+
+    /// Get the rectangles covering a range of text.
+    ///
+    /// This returns one rectangle for every run of text in the same direction, for every line
+    /// the range covers. The rectangles are ordered by line, and then visually from left to
+    /// right. They can be used to draw selection highlights.
+    pub fn range_rects(&self, range: impl RangeBounds<usize>) -> Vec<RangeRect> {
+        let range = util::resolve_range(range, self.text_buffer.string.len());
+        let mut rects: Vec<RangeRect> = vec![];
+
+        if range.is_empty() {
+            return rects;
+        }
+
+        for (line_number, (line, metric)) in self
+            .text_buffer
+            .lines
+            .iter()
+            .zip(&self.text_buffer.run_metrics)
+            .enumerate()
+        {
+            if metric.end_offset <= range.start || metric.start_offset >= range.end {
+                continue;
+            }
+
+            // Convert the range to be relative to the paragraph.
+            let paragraph_offset = self.paragraph_offset(line_number);
+            let start = range.start.saturating_sub(paragraph_offset);
+            let end = range.end - paragraph_offset;
+            let text = self.buffer().lines[line.paragraph].text();
+
+            for cluster in line.clusters() {
+                if cluster.range.end <= start || cluster.range.start >= end {
+                    continue;
+                }
+
+                // The range may only cover part of a ligature.
+                let leading = if cluster.range.start >= start {
+                    cluster.leading_edge()
+                } else {
+                    cluster.grapheme_edge(text, start)
+                };
+                let trailing = if cluster.range.end <= end {
+                    cluster.trailing_edge()
+                } else {
+                    cluster.grapheme_edge(text, end)
+                };
+
+                let (x0, x1) = (leading.min(trailing), leading.max(trailing));
+                if x0 == x1 {
+                    continue;
+                }
+
+                // Merge with the previous rectangle if they touch and go in the same direction.
+                if let Some(last) = rects.last_mut() {
+                    if last.line == line_number
+                        && last.rtl == cluster.rtl
+                        && (last.rect.x1 - f64::from(x0)).abs() < 0.01
+                    {
+                        last.rect.x1 = x1.into();
+                        continue;
+                    }
+                }
+
+                rects.push(RangeRect {
+                    rect: Rect::new(
+                        x0.into(),
+                        metric.y_offset,
+                        x1.into(),
+                        metric.y_offset + metric.height,
+                    ),
+                    line: line_number,
+                    rtl: cluster.rtl,
+                });
+            }
+        }
+
+        rects
+    }
+
+    /// Get the grapheme boundary after a text position.
+    ///
+    /// Returns the length of the text if there is none.
+    pub fn next_grapheme(&self, idx: usize) -> usize {
+        let text = self.text_buffer.string.as_str();
+        let idx = floor_char_boundary(text, idx);
+
+        GraphemeCursor::new(idx, text.len(), true)
+            .next_boundary(text, 0)
+            .ok()
+            .flatten()
+            .unwrap_or(text.len())
+    }
+
+    /// Get the grapheme boundary before a text position.
+    ///
+    /// Returns zero if there is none.
+    pub fn previous_grapheme(&self, idx: usize) -> usize {
+        let text = self.text_buffer.string.as_str();
+        let idx = floor_char_boundary(text, idx);
+
+        GraphemeCursor::new(idx, text.len(), true)
+            .prev_boundary(text, 0)
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    /// Get the end of the word that a text position is in, or of the next word after it.
+    ///
+    /// Whitespace is skipped, while punctuation counts as a word of its own. Returns the length of
+    /// the text if there is no word after the position.
+    pub fn next_word(&self, idx: usize) -> usize {
+        let text = self.text_buffer.string.as_str();
+
+        text.split_word_bound_indices()
+            .map(|(start, word)| (start + word.len(), word))
+            .find(|&(end, word)| end > idx && !is_blank(word))
+            .map_or(text.len(), |(end, _)| end)
+    }
+
+    /// Get the start of the word that a text position is in, or of the previous word before it.
+    ///
+    /// Whitespace is skipped, while punctuation counts as a word of its own. Returns zero if there
+    /// is no word before the position.
+    pub fn previous_word(&self, idx: usize) -> usize {
+        let text = self.text_buffer.string.as_str();
+
+        text.split_word_bound_indices()
+            .rev()
+            .find(|&(start, word)| start < idx && !is_blank(word))
+            .map_or(0, |(start, _)| start)
+    }
+
+    /// Move a text position one grapheme to the left, visually.
+    ///
+    /// In right-to-left text, this moves the position forwards in the text.
+    pub fn move_left(&self, idx: usize) -> usize {
+        self.move_visually(idx, false)
     }
+
+    /// Move a text position one grapheme to the right, visually.
+    ///
+    /// In right-to-left text, this moves the position backwards in the text.
+    pub fn move_right(&self, idx: usize) -> usize {
+        self.move_visually(idx, true)
+    }
+
+    /// Move a text position to the line above it.
+    ///
+    /// `x` is the horizontal position that the caret should stay as close as possible to. It
+    /// should be the X coordinate of the caret before the user started moving it between lines,
+    /// so that moving through shorter lines doesn't move the caret to the left. If it is `None`,
+    /// the current position of the caret is used.
+    ///
+    /// Positions on the first line are moved to the start of the text.
+    pub fn line_up(&self, idx: usize, x: Option<f64>) -> usize {
+        match self.line_for_index(idx) {
+            Some(line) if line > 0 => self.move_to_line(idx, line - 1, x),
+            _ => 0,
+        }
+    }
+
+    /// Move a text position to the line below it.
+    ///
+    /// `x` is the horizontal position that the caret should stay as close as possible to, see
+    /// [`line_up`](Self::line_up) for more information.
+    ///
+    /// Positions on the last line are moved to the end of the text.
+    pub fn line_down(&self, idx: usize, x: Option<f64>) -> usize {
+        match self.line_for_index(idx) {
+            Some(line) if line + 1 < self.text_buffer.lines.len() => {
+                self.move_to_line(idx, line + 1, x)
+            }
+            _ => self.text_buffer.string.len(),
+        }
+    }
+
+    /// Get the start of the line containing a text position.
+    pub fn line_start(&self, idx: usize) -> usize {
+        self.line_for_index(idx)
+            .map_or(0, |line| self.text_buffer.run_metrics[line].start_offset)
+    }
+
+    /// Get the end of the line containing a text position.
+    ///
+    /// This is the position before the paragraph separator, if there is one. For lines that were
+    /// wrapped, this is the position before the whitespace the line was wrapped at, so that the
+    /// caret stays on the same line.
+    pub fn line_end(&self, idx: usize) -> usize {
+        let line_number = match self.line_for_index(idx) {
+            Some(line) => line,
+            None => return 0,
+        };
+        let lines = &self.text_buffer.lines;
+        let line = &lines[line_number];
+        let paragraph_offset = self.paragraph_offset(line_number);
+
+        // The last line of a paragraph ends at the end of the paragraph.
+        let wrapped = lines
+            .get(line_number + 1)
+            .map_or(false, |next| next.paragraph == line.paragraph);
+        if !wrapped {
+            return paragraph_offset + line.range.end;
+        }
+
+        // Otherwise, end before the hanging whitespace, or the last grapheme if there is none.
+        let start = paragraph_offset + line.range.start;
+        let end = paragraph_offset + line.whitespace_start;
+        if line.whitespace_start < line.range.end {
+            end
+        } else {
+            self.previous_grapheme(end).max(start)
+        }
+    }
+
+    /// Move a text position to a line, staying as close as possible to an X coordinate.
+    fn move_to_line(&self, idx: usize, line_number: usize, x: Option<f64>) -> usize {
+        let x = x.unwrap_or_else(|| piet::TextLayout::hit_test_text_position(self, idx).point.x);
+        let metric = &self.text_buffer.run_metrics[line_number];
+        let y = metric.y_offset + metric.height / 2.0;
+
+        piet::TextLayout::hit_test_point(self, Point::new(x, y)).idx
+    }
+
+    /// Move a text position one grapheme to the left or right, visually.
+    fn move_visually(&self, idx: usize, right: bool) -> usize {
+        let line_number = match self.line_for_index(idx) {
+            Some(line) => line,
+            None => return 0,
+        };
+        let line = &self.text_buffer.lines[line_number];
+        let metric = &self.text_buffer.run_metrics[line_number];
+        let paragraph_offset = self.paragraph_offset(line_number);
+        let text = self.buffer().lines[line.paragraph].text();
+        let is_last = line_number + 1 == self.text_buffer.lines.len();
+
+        // Find the closest caret stop on this line in the direction we are moving.
+        let current = line.caret_x(text, idx.saturating_sub(paragraph_offset));
+        let stops = line.caret_stops(text);
+        let allowed = |&&(_, boundary): &&(f32, usize)| {
+            let stop = paragraph_offset + boundary;
+
+            // Positions at the end of a wrapped line are drawn on the next line.
+            stop != idx && (stop < metric.end_offset || is_last)
+        };
+
+        let closest = if right {
+            let after = stops.partition_point(|&(x, _)| x <= current + 0.01);
+            stops[after..].iter().find(allowed)
+        } else {
+            let before = stops.partition_point(|&(x, _)| x < current - 0.01);
+            stops[..before].iter().rev().find(allowed)
+        };
+
+        match closest {
+            Some(&(_, boundary)) => paragraph_offset + boundary,
+
+            // Move to the adjacent line, in the paragraph's direction.
+            None if right != line.rtl => self.next_grapheme(idx),
+            None => self.previous_grapheme(idx),
+        }
+    }
+}
+
+/// Round a text position down to the nearest character boundary.
+fn floor_char_boundary(text: &str, idx: usize) -> usize {
+    let mut idx = idx.min(text.len());
+    while !text.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+/// Tell whether a word only contains whitespace.
+fn is_blank(word: &str) -> bool {
+    word.chars().all(char::is_whitespace)
+}
+
+/// A rectangle covering part of a range of text.
+///
+/// This is returned by [`TextLayout::range_rects`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangeRect {
+    /// The rectangle, in layout coordinates.
+    pub rect: Rect,
+
+    /// The index of the line that the rectangle is on.
+    pub line: usize,
+
+    /// Whether the text covered by the rectangle is right-to-left.
+    pub rtl: bool,
+}
+
+/// A rectangle to paint behind text with a background color.
+///
+/// This is returned by [`TextLayout::background_rects`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackgroundRect {
+    /// The rectangle, in layout coordinates.
+    pub rect: Rect,
+
+    /// The color to fill the rectangle with.
+    pub color: piet::Color,
+
+    /// The index of the line that the rectangle is on.
+    pub line: usize,
+}
+
+/// The outline of a glyph, or of one layer of a color glyph.
+///
+/// This is returned by [`TextLayout::glyph_outlines`].
+#[derive(Debug, Clone)]
+pub struct GlyphOutline {
+    /// The outline, in layout coordinates.
+    pub path: BezPath,
+
+    /// The color to fill the outline with.
+    ///
+    /// This is `None` if the text has no color of its own, in which case the default text color
+    /// should be used.
+    pub color: Option<piet::Color>,
+
+    /// The range of text that the glyph was shaped from.
+    pub range: Range<usize>,
 }
 
+/// An iterator over the layout runs of a [`TextLayout`].
+#[derive(Clone)]
+pub struct LayoutRunIter<'a> {
+    /// The buffer containing the text of the lines.
+    buffer: &'a Buffer,
+
+    /// The lines to iterate over.
+    lines: slice::Iter<'a, Line>,
+}
+
+impl<'a> LayoutRunIter<'a> {
+    fn new(buffer: &'a Buffer, lines: &'a [Line]) -> Self {
+        Self {
+            buffer,
+            lines: lines.iter(),
+        }
+    }
+
+    fn make_run(&self, line: &'a Line) -> LayoutRun<'a> {
+        LayoutRun {
+            line_i: line.paragraph,
+            text: self.buffer.lines[line.paragraph].text(),
+            rtl: line.rtl,
+            glyphs: &line.glyphs,
+            line_y: line.baseline,
+            line_top: line.top,
+            line_w: line.width,
+        }
+    }
+}
+
+impl<'a> Iterator for LayoutRunIter<'a> {
+    type Item = LayoutRun<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next()?;
+        Some(self.make_run(line))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.lines.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for LayoutRunIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let line = self.lines.next_back()?;
+        Some(self.make_run(line))
+    }
+}
+
+impl ExactSizeIterator for LayoutRunIter<'_> {}
+
 impl piet::TextLayout for TextLayout {
     fn size(&self) -> Size {
         if let Some(size) = self.text_buffer.logical_size.get() {
             return size;
         }
 
-        let mut size = Size::new(f64::MIN, f64::MIN);
-
-        for run in self.layout_runs() {
-            let max = |a: f32, b: f64| {
-                let a: f64 = a.into();
-                if a < b {
-                    b
-                } else {
-                    a
-                }
-            };
+        let mut size = Size::ZERO;
 
-            size.width = max(run.line_w, size.width);
-            size.height = max(run.line_y, size.height);
+        for line in &self.text_buffer.lines {
+            size.width = size.width.max(line.width.into());
+            size.height = size.height.max((line.top + line.height).into());
         }
 
         self.text_buffer.logical_size.set(Some(size));
@@ -204,8 +997,11 @@ impl piet::TextLayout for TextLayout {
     }
 
     fn trailing_whitespace_width(&self) -> f64 {
-        // TODO: This doesn't matter I think.
-        self.size().width
+        self.text_buffer
+            .lines
+            .iter()
+            .map(|line| f64::from(line.width + line.whitespace_width))
+            .fold(0.0, f64::max)
     }
 
     fn image_bounds(&self) -> Rect {
@@ -217,10 +1013,10 @@ impl piet::TextLayout for TextLayout {
     }
 
     fn line_text(&self, line_number: usize) -> Option<&str> {
-        self.buffer()
-            .layout_runs()
-            .nth(line_number)
-            .map(|run| run.text)
+        self.text_buffer
+            .run_metrics
+            .get(line_number)
+            .map(|metric| &self.text_buffer.string[metric.range()])
     }
 
     fn line_metric(&self, line_number: usize) -> Option<piet::LineMetric> {
@@ -228,101 +1024,71 @@ impl piet::TextLayout for TextLayout {
     }
 
     fn line_count(&self) -> usize {
-        self.buffer().layout_runs().count()
+        self.text_buffer.lines.len()
     }
 
     fn hit_test_point(&self, point: Point) -> piet::HitTestPoint {
         let mut htp = piet::HitTestPoint::default();
-        let (x, y) = point.into();
-
-        if let Some(cursor) = self.buffer().hit(x as f32, y as f32) {
-            htp.idx = cursor.index;
-            htp.is_inside = true;
-            return htp;
-        }
-
-        let mut ink_context = self.text_buffer.handle.borrow_ink();
-        let mut font_system_guard = match self.text_buffer.handle.borrow_font_system() {
-            Some(system) => system,
+        let lines = &self.text_buffer.lines;
+        let metrics = &self.text_buffer.run_metrics;
+        let x = point.x as f32;
+
+        // Find the line containing the point, or the closest one to it.
+        let line_number = match metrics
+            .iter()
+            .position(|metric| point.y < metric.y_offset + metric.height)
+        {
+            Some(line) => line,
+            None if metrics.is_empty() => return htp,
+            None => metrics.len() - 1,
+        };
+        let line = &lines[line_number];
+        let metric = &metrics[line_number];
+        let inside_line = point.y >= metric.y_offset && point.y < metric.y_offset + metric.height;
+
+        // Find the cluster containing the point, or the closest one to it.
+        let paragraph_offset = self.paragraph_offset(line_number);
+        let (cluster, inside_cluster) = match line.cluster_at_x(x) {
+            Some(found) => found,
             None => {
-                warn!("Tried to borrow font system to calculate better hit test point, but it was already borrowed.");
-                htp.idx = 0;
-                htp.is_inside = false;
+                // There is no text on this line.
+                htp.idx = metric.start_offset;
                 return htp;
             }
         };
-        let font_system = &mut font_system_guard
-            .get()
-            .expect("For a TextLayout to exist, the font system must have already been initialized")
-            .system;
-
-        // Look for the glyph with the closest distance to the point.
-        let mut closest_distance = f64::MAX;
-
-        for (glyph, physical_glyph) in self.layout_runs().flat_map(|run| {
-            let run_y = run.line_y;
-            run.glyphs
-                .iter()
-                .map(move |glyph| (glyph, glyph.physical((0., run_y), 1.)))
-        }) {
-            let bounding_box = match ink_context.bounding_box(&physical_glyph, font_system) {
-                Some(bbox) => bbox,
-                None => continue,
-            };
-
-            // If the point is inside of the bounding box, this is definitely it.
-            if bounding_box.contains(point) {
-                htp.idx = glyph.start;
-                htp.is_inside = false;
-                return htp;
-            }
 
-            // Otherwise, find the distance from the midpoint.
-            let midpoint = bounding_box.center();
-            let distance = midpoint.distance(point);
-            if distance < closest_distance {
-                closest_distance = distance;
-                htp.idx = glyph.start;
-            }
+        let text = self.buffer().lines[line.paragraph].text();
+        htp.idx = paragraph_offset + cluster.hit(text, x);
+
+        // Don't put the caret on the next line when the point is past the end of a wrapped line.
+        if htp.idx >= metric.end_offset && line_number + 1 < lines.len() {
+            htp.idx = paragraph_offset + cluster.range.start;
         }
 
-        // If we didn't find anything, just return the closest index.
-        htp.is_inside = false;
+        htp.is_inside = inside_line && inside_cluster;
         htp
     }
 
-    fn hit_test_text_position(&self, idx: usize) -> piet::HitTestPosition {
-        // Iterator over glyphs and their assorted lines.
-        let mut lines_and_glyphs = self.layout_runs().enumerate().flat_map(|(line, run)| {
-            run.glyphs.iter().map(move |glyph| {
-                (
-                    line,
-                    {
-                        // Get the point.
-                        let physical = glyph.physical((0.0, 0.0), 1.0);
-                        let x = physical.x as f64;
-                        let y = run.line_y as f64
-                            + physical.y as f64
-                            + self.text_buffer.glyph_size as f64;
-
-                        Point::new(x, y)
-                    },
-                    glyph.start..glyph.end,
-                )
-            })
-        });
+    fn rects_for_range(&self, range: impl RangeBounds<usize>) -> Vec<Rect> {
+        self.range_rects(range)
+            .into_iter()
+            .map(|range_rect| range_rect.rect)
+            .collect()
+    }
 
-        let (line, point, _) = match lines_and_glyphs.find(|(_, _, range)| range.contains(&idx)) {
-            Some(x) => x,
-            None => {
-                // TODO: What are you supposed to do here?
-                return piet::HitTestPosition::default();
-            }
+    fn hit_test_text_position(&self, idx: usize) -> piet::HitTestPosition {
+        let mut htp = piet::HitTestPosition::default();
+        let line_number = match self.line_for_index(idx) {
+            Some(line) => line,
+            None => return htp,
         };
 
-        let mut htp = piet::HitTestPosition::default();
-        htp.point = point;
-        htp.line = line;
+        let line = &self.text_buffer.lines[line_number];
+        let text = self.buffer().lines[line.paragraph].text();
+        let index = idx.saturating_sub(self.paragraph_offset(line_number));
+
+        htp.point = Point::new(line.caret_x(text, index).into(), line.baseline.into());
+        htp.line = line_number;
         htp
     }
 }
@@ -359,21 +1125,24 @@ struct RunMetrics {
 }
 
 impl RunMetrics {
-    fn new(run: ct::LayoutRun<'_>, glyph_size: f64) -> RunMetrics {
-        let (start_offset, end_offset) = run.glyphs.iter().fold((0, 0), |(start, end), glyph| {
-            (cmp::min(start, glyph.start), cmp::max(end, glyph.end))
-        });
+    fn new(line: &Line, offset: usize, end_offset: usize) -> RunMetrics {
+        let start_offset = offset + line.range.start;
 
-        let y_offset = run.line_top.into();
-        let baseline = run.line_y as f64 - run.line_top as f64;
+        // Trailing whitespace covers the hanging whitespace glyphs, as well as the paragraph
+        // separator if this is the last line of the paragraph. Text that was cut off isn't
+        // visible, so it doesn't count.
+        let visible_end = line.truncated.map_or(end_offset, |start| offset + start);
+        let trailing_whitespace = visible_end.saturating_sub(offset + line.whitespace_start);
+        let y_offset = line.top.into();
+        let baseline = line.baseline as f64 - line.top as f64;
 
         RunMetrics {
             line_metric: piet::LineMetric {
                 start_offset,
                 end_offset,
-                trailing_whitespace: 0, // TODO
+                trailing_whitespace,
                 y_offset,
-                height: glyph_size as _,
+                height: line.height.into(),
                 baseline,
             },
         }
@@ -385,8 +1154,11 @@ pub(crate) struct InkRectangleState {
     /// The swash scaling context.
     scaler: ScaleContext,
 
+    /// Whether glyphs are hinted.
+    hinting: bool,
+
     /// Cache between fonts, glyphs and their bounding boxes.
-    bbox_cache: HashMap<ct::CacheKey, Option<Rect>>,
+    bbox_cache: HashMap<(ct::CacheKey, Synthesis), Option<Rect>>,
 
     /// Swash image buffer.
     swash_image: SwashImage,
@@ -399,20 +1171,30 @@ impl InkRectangleState {
     pub(crate) fn new() -> Self {
         Self {
             scaler: ScaleContext::new(),
+            hinting: RenderOptions::default().hinting,
             bbox_cache: HashMap::new(),
             swash_image: SwashImage::new(),
             swash_outline: SwashOutline::new(),
         }
     }
 
+    /// Set whether glyphs are hinted.
+    fn set_hinting(&mut self, hinting: bool) {
+        if hinting != self.hinting {
+            self.hinting = hinting;
+            self.bbox_cache.clear();
+        }
+    }
+
     /// Get the bounding box for a glyph.
     fn bounding_box(
         &mut self,
         glyph: &ct::PhysicalGlyph,
+        synthesis: Synthesis,
         system: &mut ct::FontSystem,
     ) -> Option<Rect> {
         // If we already have the bounding box here, return it.
-        let entry = match self.bbox_cache.entry(glyph.cache_key) {
+        let entry = match self.bbox_cache.entry((glyph.cache_key, synthesis)) {
             Entry::Occupied(o) => return *o.into_mut(),
             Entry::Vacant(v) => v,
         };
@@ -422,15 +1204,19 @@ impl InkRectangleState {
         // Find the font.
         if let Some(font) = system.get_font(glyph.cache_key.font_id) {
             // Create a scaler for this font.
+            let font_size = f32::from_bits(glyph.cache_key.font_size_bits);
             let mut scaler = self
                 .scaler
                 .builder(font.as_swash())
-                .size(f32::from_bits(glyph.cache_key.font_size_bits))
+                .size(font_size)
+                .hint(self.hinting)
+                .normalized_coords(entry.key().1.variations.coords())
                 .build();
 
             // See if we can get an outline.
             self.swash_outline.clear();
             if scaler.scale_outline_into(glyph.cache_key.glyph_id, &mut self.swash_outline) {
+                entry.key().1.apply(&mut self.swash_outline, font_size);
                 bbox = Some(cvt_bounds(self.swash_outline.bounds()));
             } else {
                 // See if we can get a bitmap.
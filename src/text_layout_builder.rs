@@ -16,11 +16,16 @@
 // You should have received a copy of the GNU Lesser General Public License and the Mozilla
 // Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::attributes::Attributes;
+use crate::attributes::{Attribute, Attributes, ExtendedAttrs, ExtendedAttrsTable};
+use crate::layout;
 use crate::metadata::Metadata;
+use crate::shaping;
 use crate::text::{FontSystemAndDefaults, Text};
 use crate::text_layout::TextLayout;
-use crate::{cvt_color, cvt_family, cvt_style, cvt_weight, FontError, POINTS_PER_INCH};
+use crate::{
+    cvt_color, cvt_family, cvt_style, cvt_weight, DecorationLine, DecorationStyle, FontError,
+    FontFeature, FontVariation, LineHeight, POINTS_PER_INCH,
+};
 
 use cosmic_text as ct;
 use ct::{Attrs, Buffer, BufferLine, Metrics};
@@ -51,6 +56,24 @@ pub struct TextLayoutBuilder {
     /// Alignment for the text.
     alignment: Option<TextAlignment>,
 
+    /// The height of each line.
+    line_height: LineHeight,
+
+    /// How lines are wrapped.
+    wrap_mode: WrapMode,
+
+    /// What to do with lines that are too long.
+    overflow: TextOverflow,
+
+    /// The maximum number of lines.
+    max_lines: Option<usize>,
+
+    /// The marker placed at the end of truncated text.
+    ellipsis: String,
+
+    /// How the text is shaped.
+    shaping_mode: ShapingMode,
+
     /// The range attributes.
     range_attributes: Attributes,
 
@@ -63,6 +86,62 @@ pub struct TextLayoutBuilder {
     error: Option<Error>,
 }
 
+/// How lines are wrapped when they are wider than the maximum width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum WrapMode {
+    /// Wrap lines between words.
+    Word,
+
+    /// Wrap lines between any two glyphs.
+    ///
+    /// This is useful for text that doesn't use spaces, such as long URLs.
+    Glyph,
+
+    /// Don't wrap lines at all, even if a maximum width is set.
+    None,
+}
+
+/// What to do with lines that are still wider than the maximum width after wrapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TextOverflow {
+    /// Leave the line as it is, extending past the maximum width.
+    Visible,
+
+    /// Cut off the end of the line and replace it with an ellipsis, so that it fits.
+    Ellipsis,
+}
+
+/// How text is shaped into glyphs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ShapingMode {
+    /// Use basic shaping when it gives the same result as advanced shaping, and advanced shaping
+    /// otherwise.
+    ///
+    /// Basic shaping is used for left-to-right text without combining characters, as long as
+    /// none of the fonts used would apply kerning, ligatures or other substitutions to it, no
+    /// OpenType features were requested with [`TextLayoutBuilder::range_features`], and only the
+    /// default instances of variable fonts are used.
+    ///
+    /// The shaper is picked before the text is shaped, so the text is only shaped once. Picking
+    /// it costs a character map lookup for every character, plus a search of the font's layout
+    /// tables the first time each glyph is seen, which the [`Text`] remembers. Most fonts kern
+    /// common letters, in which case this cost is paid on top of advanced shaping.
+    Automatic,
+
+    /// Map every character directly to a glyph.
+    ///
+    /// This is much cheaper than advanced shaping, but doesn't apply kerning, ligatures,
+    /// requested OpenType features or the advances of variable font instances, doesn't fall back
+    /// to other fonts for missing characters, and doesn't handle complex scripts properly.
+    Basic,
+
+    /// Shape text using `rustybuzz`.
+    Advanced,
+}
+
 impl fmt::Debug for TextLayoutBuilder {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("TextLayoutBuilder")
@@ -76,6 +155,12 @@ impl fmt::Debug for TextLayoutBuilder {
 impl TextLayoutBuilder {
     pub(crate) fn new(text: Text, string: impl TextStorage) -> Self {
         Self {
+            line_height: text.line_height(),
+            wrap_mode: WrapMode::Word,
+            overflow: TextOverflow::Visible,
+            max_lines: None,
+            shaping_mode: ShapingMode::Automatic,
+            ellipsis: "\u{2026}".into(),
             handle: text,
             string: Box::new(string),
             defaults: util::LayoutDefaults::default(),
@@ -87,9 +172,186 @@ impl TextLayoutBuilder {
         }
     }
 
-    fn shaping(&self) -> ct::Shaping {
-        // TODO: Use a better strategy to find this!
-        ct::Shaping::Advanced
+    /// Set the height of each line.
+    ///
+    /// This overrides the default line height set using [`Text::set_line_height`].
+    pub fn line_height(mut self, line_height: LineHeight) -> Self {
+        self.line_height = line_height;
+        self
+    }
+
+    /// Set how lines are wrapped when they are wider than the maximum width.
+    ///
+    /// By default, lines are wrapped between words.
+    pub fn wrap_mode(mut self, wrap_mode: WrapMode) -> Self {
+        self.wrap_mode = wrap_mode;
+        self
+    }
+
+    /// Set what to do with lines that are still wider than the maximum width after wrapping.
+    ///
+    /// By default, they are left as they are. This also determines what happens to the last
+    /// line when the text is cut off by [`max_lines`](Self::max_lines).
+    pub fn text_overflow(mut self, overflow: TextOverflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set the maximum number of lines to lay out.
+    ///
+    /// Any text past the last line is cut off. If the text overflow is set to
+    /// [`TextOverflow::Ellipsis`], the last line ends with an ellipsis. Use
+    /// [`TextLayout::truncated_ranges`] to find out which text was cut off.
+    ///
+    /// At least one line is always laid out.
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = Some(max_lines);
+        self
+    }
+
+    /// Set the marker placed at the end of lines that were cut off.
+    ///
+    /// This is shaped using the attributes of the text it replaces. By default, this is
+    /// "\u{2026}" (a horizontal ellipsis).
+    pub fn ellipsis(mut self, ellipsis: impl Into<String>) -> Self {
+        self.ellipsis = ellipsis.into();
+        self
+    }
+
+    /// Set how the text is shaped into glyphs.
+    ///
+    /// By default, this is [`ShapingMode::Automatic`]. Use [`TextLayout::shaping_mode`] to find
+    /// out which shaper was actually used.
+    pub fn shaping_mode(mut self, mode: ShapingMode) -> Self {
+        self.shaping_mode = mode;
+        self
+    }
+
+    /// Apply OpenType features to a range of text when it is shaped.
+    ///
+    /// Later features replace earlier ones with the same tag. Features are ignored when the text
+    /// is shaped with [`ShapingMode::Basic`]. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_features(
+        mut self,
+        range: impl RangeBounds<usize>,
+        features: impl IntoIterator<Item = FontFeature>,
+    ) -> Self {
+        self.push_range(range, Attribute::Features(features.into_iter().collect()));
+        self
+    }
+
+    /// Set the axes of variable fonts for a range of text.
+    ///
+    /// The values are used for shaping, for ink rectangles and for rasterizing the text. Later
+    /// values replace earlier ones for the same axis. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_variations(
+        mut self,
+        range: impl RangeBounds<usize>,
+        variations: impl IntoIterator<Item = FontVariation>,
+    ) -> Self {
+        self.push_range(
+            range,
+            Attribute::Variations(variations.into_iter().collect()),
+        );
+        self
+    }
+
+    /// Add extra space after every grapheme cluster in a range of text.
+    ///
+    /// The spacing is in points, and is scaled by the DPI like font sizes are. Negative values
+    /// bring the text closer together. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_letter_spacing(mut self, range: impl RangeBounds<usize>, spacing: f64) -> Self {
+        self.push_range(range, Attribute::LetterSpacing(spacing));
+        self
+    }
+
+    /// Add extra space after every word separator, such as a space, in a range of text.
+    ///
+    /// This is added on top of any letter spacing. The spacing is in points, and is scaled by
+    /// the DPI like font sizes are. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_word_spacing(mut self, range: impl RangeBounds<usize>, spacing: f64) -> Self {
+        self.push_range(range, Attribute::WordSpacing(spacing));
+        self
+    }
+
+    /// Paint a background color behind a range of text.
+    ///
+    /// The layout doesn't paint anything itself; use [`TextLayout::background_rects`] to get the
+    /// rectangles to paint beneath the glyphs. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_background(mut self, range: impl RangeBounds<usize>, color: piet::Color) -> Self {
+        self.push_range(range, Attribute::Background(color));
+        self
+    }
+
+    /// Draw a line above a range of text.
+    ///
+    /// Ranges are ordered as described on [`range_attribute`](#method.range_attribute).
+    pub fn range_overline(mut self, range: impl RangeBounds<usize>, overline: bool) -> Self {
+        self.push_range(range, Attribute::Overline(overline));
+        self
+    }
+
+    /// Set the style of the underlines, overlines and strikethroughs of a range of text.
+    ///
+    /// This doesn't add any lines by itself. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_decoration_style(
+        mut self,
+        range: impl RangeBounds<usize>,
+        style: DecorationStyle,
+    ) -> Self {
+        self.push_range(range, Attribute::DecorationStyle(style));
+        self
+    }
+
+    /// Set the color of the underlines, overlines and strikethroughs of a range of text.
+    ///
+    /// By default, lines have the color of the text. The colors are only used by
+    /// [`TextLayout::decoration_lines`]. Use
+    /// [`range_decoration_line_color`](Self::range_decoration_line_color) to set the color of
+    /// one kind of line. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_decoration_color(
+        mut self,
+        range: impl RangeBounds<usize>,
+        color: piet::Color,
+    ) -> Self {
+        self.push_range(range, Attribute::DecorationColor(None, color));
+        self
+    }
+
+    /// Set the color of one kind of decoration line for a range of text.
+    ///
+    /// This replaces the color set by [`range_decoration_color`](Self::range_decoration_color)
+    /// for that kind of line, so that, for instance, underlines and strikethroughs can have
+    /// different colors. Ranges are ordered as described on
+    /// [`range_attribute`](#method.range_attribute).
+    pub fn range_decoration_line_color(
+        mut self,
+        range: impl RangeBounds<usize>,
+        line: DecorationLine,
+        color: piet::Color,
+    ) -> Self {
+        self.push_range(range, Attribute::DecorationColor(Some(line), color));
+        self
+    }
+
+    /// Add an attribute to a range of text.
+    fn push_range(&mut self, range: impl RangeBounds<usize>, attr: Attribute) {
+        let range = util::resolve_range(range, self.string.len());
+
+        debug_assert!(
+            range.start >= self.last_range_start_pos,
+            "attributes must be added in non-decreasing start order"
+        );
+        self.last_range_start_pos = range.start;
+
+        self.range_attributes.push(range, attr);
     }
 }
 
@@ -111,32 +373,31 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
         self
     }
 
+    /// Apply an attribute to a range of text.
+    ///
+    /// This and the other `range_*` methods must be called in non-decreasing order of range
+    /// starts. Where ranges overlap, later attributes replace earlier ones.
     fn range_attribute(
         mut self,
         range: impl RangeBounds<usize>,
         attribute: impl Into<TextAttribute>,
     ) -> Self {
-        let range = util::resolve_range(range, self.string.len());
-        let attribute = attribute.into();
-
-        debug_assert!(
-            range.start >= self.last_range_start_pos,
-            "attributes must be added in non-decreasing start order"
-        );
-        self.last_range_start_pos = range.start;
-
-        self.range_attributes.push(range, attribute);
-
+        self.push_range(range, Attribute::Piet(attribute.into()));
         self
     }
 
     fn build(self) -> Result<Self::Out, Error> {
-        let shaping = self.shaping();
         let Self {
             handle,
             string,
             defaults,
             max_width,
+            line_height,
+            wrap_mode,
+            overflow,
+            max_lines,
+            ellipsis,
+            shaping_mode,
             mut range_attributes,
             error,
             ..
@@ -162,9 +423,25 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
         // Get the font size and line height.
         let font_size = defaults.font_size * handle.dpi() / POINTS_PER_INCH;
 
-        // NOTE: Pango uses a default line height of 0, and piet-cairo doesn't appear to
-        // change this.
-        let metrics = Metrics::new(font_size as _, font_size as _);
+        let line_height_px = match line_height {
+            LineHeight::Relative(multiple) => font_size * multiple,
+            LineHeight::Absolute(points) => points * handle.dpi() / POINTS_PER_INCH,
+        };
+        let metrics = Metrics::new(font_size as _, line_height_px as _);
+
+        // The attributes that cosmic-text can't handle on its own.
+        let mut table = ExtendedAttrsTable::new(
+            ExtendedAttrs {
+                font_size: font_size as f32,
+                features: vec![],
+                variations: vec![],
+                letter_spacing: 0.0,
+                word_spacing: 0.0,
+                background: None,
+                decoration_colors: [None; 3],
+            },
+            handle.dpi(),
+        );
 
         // Get the default attributes for the layout.
         let default_attrs = {
@@ -173,6 +450,7 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
             metadata.set_underline(defaults.underline);
             metadata.set_strikethrough(defaults.strikethrough);
             metadata.set_boldness(defaults.weight);
+            metadata.set_italic(defaults.style == piet::FontStyle::Italic);
 
             let mut attrs = Attrs::new()
                 .family(cvt_family(&defaults.font))
@@ -189,19 +467,58 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
 
         // Re-use memory from a previous layout.
         let mut buffer_lines = handle.take_buffer();
-        let mut offset = 0;
+        let mut paragraph_offsets = vec![];
+
+        // Paragraph separators may be more than one byte long, so figure out where each line
+        // actually starts.
+        let mut paragraphs = ct::BidiParagraphs::new(&string)
+            .map(|line| (line.as_ptr() as usize - string.as_ptr() as usize, line))
+            .collect::<Vec<_>>();
+
+        // A trailing paragraph separator starts one last, empty line, as does an empty string.
+        let text_end = paragraphs
+            .last()
+            .map_or(0, |(start, line)| start + line.len());
+        if string.is_empty() || text_end < string.len() {
+            paragraphs.push((string.len(), ""));
+        }
 
-        for line in ct::BidiParagraphs::new(&string) {
-            let start = offset;
+        let mut paragraph_attrs = vec![];
+        for (start, line) in paragraphs {
             let end = start + line.len() + 1;
+            paragraph_offsets.push(start);
 
             // Get the attributes for this line.
             let attrs_list = range_attributes.text_attributes(
                 font_system,
+                &mut table,
                 start..end,
                 default_attrs.as_attrs(),
             )?;
 
+            paragraph_attrs.push((line, attrs_list));
+        }
+
+        // Pick the shaper to use. Simple text is shaped with the basic shaper if the fonts
+        // wouldn't do anything more with it.
+        let shaping = match shaping_mode {
+            ShapingMode::Automatic
+                if shaping::is_simple(&string)
+                    && !range_attributes.has_features()
+                    && shaping::basic_is_exact(
+                        &paragraph_attrs,
+                        &table,
+                        &mut font_system.system,
+                        &mut font_system.shaping,
+                    ) =>
+            {
+                ct::Shaping::Basic
+            }
+            ShapingMode::Basic => ct::Shaping::Basic,
+            _ => ct::Shaping::Advanced,
+        };
+
+        for (line, attrs_list) in paragraph_attrs {
             let mut line = BufferLine::new(line, attrs_list, shaping);
             line.set_align(self.alignment.map(|a| match a {
                 TextAlignment::Start => ct::Align::Left,
@@ -211,8 +528,6 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
             }));
 
             buffer_lines.push(line);
-
-            offset = end;
         }
 
         let mut buffer = {
@@ -221,7 +536,14 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
 
             buffer.lines = buffer_lines;
             buffer.set_size(system, max_width as f32, f32::INFINITY);
-            buffer.set_wrap(system, ct::Wrap::Word);
+            buffer.set_wrap(
+                system,
+                match wrap_mode {
+                    WrapMode::Word => ct::Wrap::Word,
+                    WrapMode::Glyph => ct::Wrap::Glyph,
+                    WrapMode::None => ct::Wrap::None,
+                },
+            );
 
             // Shape the buffer.
             buffer.shape_until_scroll(system);
@@ -233,16 +555,33 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
         fix_shaping_holes(
             &mut buffer,
             &mut range_attributes,
+            &mut table,
+            &paragraph_offsets,
             default_attrs.as_attrs(),
+            shaping,
             font_system,
         )?;
 
+        // Lay out the text.
+        let options = layout::Options {
+            line_height,
+            overflow,
+            shaping,
+            max_lines,
+            ellipsis,
+        };
+        let lines = layout::layout(&buffer, &table, &options, &mut font_system.system);
+
         Ok(TextLayout::new(
             handle.clone(),
             buffer,
             string,
             font_size as i32,
             &mut font_system.system,
+            lines,
+            table,
+            &paragraph_offsets,
+            shaping,
         ))
     }
 }
@@ -251,18 +590,42 @@ impl piet::TextLayoutBuilder for TextLayoutBuilder {
 fn fix_shaping_holes(
     buffer: &mut Buffer,
     attributes: &mut Attributes,
+    table: &mut ExtendedAttrsTable,
+    offsets: &[usize],
     attrs: Attrs<'_>,
+    shaping: ct::Shaping,
     system: &mut FontSystemAndDefaults,
 ) -> Result<(), Error> {
+    // The basic shaper doesn't fall back to other fonts, and doesn't tell us where the holes are.
+    if shaping == ct::Shaping::Basic {
+        return Ok(());
+    }
+
     // First, try clearing the font.
-    if fill_holes(buffer, system, attrs, attributes, FillType::ClearFont)? {
+    if fill_holes(
+        buffer,
+        system,
+        table,
+        offsets,
+        attrs,
+        attributes,
+        FillType::ClearFont,
+    )? {
         buffer.shape_until_scroll(&mut system.system);
     } else {
         return Ok(());
     }
 
     // Then, try clearing the style.
-    if fill_holes(buffer, system, attrs, attributes, FillType::ClearStyle)? {
+    if fill_holes(
+        buffer,
+        system,
+        table,
+        offsets,
+        attrs,
+        attributes,
+        FillType::ClearStyle,
+    )? {
         buffer.shape_until_scroll(&mut system.system);
     } else {
         return Ok(());
@@ -289,14 +652,15 @@ enum FillType {
 fn fill_holes(
     buffer: &mut Buffer,
     system: &mut FontSystemAndDefaults,
+    table: &mut ExtendedAttrsTable,
+    offsets: &[usize],
     defaults: Attrs<'_>,
     attributes: &mut Attributes,
     ty: FillType,
 ) -> Result<bool, Error> {
     let mut found_holes = false;
-    let mut offset = 0;
 
-    for line in &mut buffer.lines {
+    for (line, &offset) in buffer.lines.iter_mut().zip(offsets) {
         let holes = find_holes(line);
 
         if holes.is_empty() {
@@ -305,9 +669,21 @@ fn fill_holes(
 
         found_holes = true;
 
-        // Try to fill the holes.
+        // Keep the requested emphasis of the holes, so that it can be synthesized if the style
+        // has to be cleared.
         let original = line.attrs_list();
+        let emphasis = holes
+            .iter()
+            .map(|range| {
+                let metadata = Metadata::from_raw(original.get_span(range.start).metadata);
+                (range.clone(), metadata)
+            })
+            .collect::<Vec<_>>();
+
+        // Try to fill the holes.
         for range in holes {
+            let absolute = offset + range.start..offset + range.end;
+
             // Figure out the replacement attribute.
             match ty {
                 FillType::ClearFont => {
@@ -333,29 +709,53 @@ fn fill_holes(
                         }
                     };
 
-                    attributes.push(range, TextAttribute::FontFamily(family));
+                    attributes.push(absolute, TextAttribute::FontFamily(family));
                 }
 
                 FillType::ClearStyle => {
                     attributes.push(
-                        range.clone(),
+                        absolute.clone(),
                         TextAttribute::Style(piet::FontStyle::Regular),
                     );
-                    attributes.push(range, TextAttribute::Weight(piet::FontWeight::NORMAL));
+                    attributes.push(absolute, TextAttribute::Weight(piet::FontWeight::NORMAL));
                 }
             };
         }
 
         // Set the new attributes.
         let end = offset + line.text().len() + 1;
-        let attrs_list = attributes.text_attributes(system, offset..end, defaults)?;
+        let mut attrs_list = attributes.text_attributes(system, table, offset..end, defaults)?;
+
+        if let FillType::ClearStyle = ty {
+            for (range, requested) in emphasis {
+                restore_emphasis(&mut attrs_list, range, requested);
+            }
+        }
+
         line.set_attrs_list(attrs_list);
-        offset = end;
     }
 
     Ok(found_holes)
 }
 
+/// Set the boldness and italics of a range back to the ones that were requested.
+fn restore_emphasis(attrs_list: &mut ct::AttrsList, range: Range<usize>, requested: Metadata) {
+    let mut spans = vec![(range.clone(), ct::AttrsOwned::new(attrs_list.defaults()))];
+    spans.extend(attrs_list.spans().into_iter().filter_map(|(span, attrs)| {
+        let start = cmp::max(span.start, range.start);
+        let end = cmp::min(span.end, range.end);
+        (start < end).then(|| (start..end, attrs.clone()))
+    }));
+
+    for (span, mut attrs) in spans {
+        let mut metadata = Metadata::from_raw(attrs.metadata);
+        metadata.set_boldness(requested.boldness());
+        metadata.set_italic(requested.italic());
+        attrs.metadata = metadata.into_raw();
+        attrs_list.add_span(span, attrs.as_attrs());
+    }
+}
+
 /// Find holes where the text is not rendered.
 fn find_holes(line: &BufferLine) -> TinyVec<[Range<usize>; 1]> {
     let mut holes = TinyVec::new();
@@ -0,0 +1,234 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+use cosmic_text::CacheKey;
+use piet::{Color, FontFamily, Text as _, TextLayoutBuilder as _};
+use piet_cosmic_text::{Antialiasing, DirtyRect, GlyphAtlas, Text, TextLayout};
+
+const WIDTH: usize = 200;
+const HEIGHT: usize = 60;
+
+fn text() -> Text {
+    let text = Text::new();
+    text.wait_for_load_blocking();
+    text
+}
+
+fn layout(text: &mut Text, string: &str) -> TextLayout {
+    text.new_text_layout(string.to_owned())
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .build()
+        .unwrap()
+}
+
+fn cache_keys(layout: &TextLayout) -> Vec<CacheKey> {
+    layout
+        .layout_runs()
+        .flat_map(|run| {
+            run.glyphs
+                .iter()
+                .map(move |glyph| glyph.physical((0.0, run.line_y), 1.0).cache_key)
+        })
+        .collect()
+}
+
+#[test]
+fn quads_match_draw_into() {
+    let mut text = text();
+    let layout = layout(&mut text, "Hello, world!");
+    let mut atlas = GlyphAtlas::new(256, 1);
+    let quads = atlas.prepare(&layout, (3.0, 5.0), Color::BLACK).unwrap();
+
+    // Spaces don't get a quad.
+    assert_eq!(quads.len(), "Hello,world!".len());
+
+    // Composite the quads the way a GPU would.
+    let page = atlas.page(0).unwrap();
+    let size = f64::from(atlas.page_size());
+    let mut alphas = vec![0u8; WIDTH * HEIGHT];
+    for quad in &quads {
+        assert_eq!(quad.page, 0);
+        assert_eq!(quad.color, Color::BLACK);
+        assert!(quad.uv.x0 >= 0.0 && quad.uv.y0 >= 0.0);
+        assert!(quad.uv.x1 <= 1.0 && quad.uv.y1 <= 1.0);
+        assert_eq!(quad.uv.width() * size, quad.rect.width());
+        assert_eq!(quad.uv.height() * size, quad.rect.height());
+
+        for y in 0..quad.rect.height() as usize {
+            for x in 0..quad.rect.width() as usize {
+                let src_x = (quad.uv.x0 * size) as usize + x;
+                let src_y = (quad.uv.y0 * size) as usize + y;
+                let alpha = u32::from(page[(src_y * size as usize + src_x) * 4 + 3]);
+
+                let dst_x = quad.rect.x0 as usize + x;
+                let dst_y = quad.rect.y0 as usize + y;
+                if dst_x >= WIDTH || dst_y >= HEIGHT {
+                    continue;
+                }
+
+                let dst = &mut alphas[dst_y * WIDTH + dst_x];
+                *dst = (alpha + u32::from(*dst) * (255 - alpha) / 255) as u8;
+            }
+        }
+    }
+
+    let mut pixels = vec![0; WIDTH * HEIGHT * 4];
+    layout
+        .draw_into(
+            &mut pixels,
+            WIDTH,
+            HEIGHT,
+            WIDTH * 4,
+            (3.0, 5.0),
+            Color::BLACK,
+        )
+        .unwrap();
+
+    for (expected, actual) in pixels.chunks(4).map(|pixel| pixel[3]).zip(alphas) {
+        assert!(
+            (i32::from(expected) - i32::from(actual)).abs() <= 1,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+}
+
+#[test]
+fn dirty_rects() {
+    let mut text = text();
+    let mut atlas = GlyphAtlas::new(256, 2);
+    assert!(atlas.is_empty());
+
+    // A new page is uploaded all at once.
+    let hello = layout(&mut text, "Hello");
+    atlas.prepare(&hello, (0.0, 0.0), Color::BLACK).unwrap();
+    let count = atlas.len();
+    assert!(count > 0);
+    assert_eq!(
+        atlas.take_dirty_rects(),
+        [DirtyRect {
+            page: 0,
+            x: 0,
+            y: 0,
+            width: 256,
+            height: 256
+        }]
+    );
+
+    // Glyphs that are already in the atlas are reused.
+    atlas.next_frame();
+    let quads = atlas.prepare(&hello, (0.0, 0.0), Color::BLACK).unwrap();
+    assert_eq!(quads.len(), 5);
+    assert!(atlas.take_dirty_rects().is_empty());
+
+    // New glyphs only dirty their own area.
+    let world = layout(&mut text, "world");
+    let quads = atlas.prepare(&world, (0.0, 0.0), Color::BLACK).unwrap();
+    assert!(atlas.len() > count);
+
+    let dirty = atlas.take_dirty_rects();
+    assert_eq!(dirty.len(), atlas.len() - count);
+    for rect in dirty {
+        assert!(rect.width < 30 && rect.height < 30);
+        assert!(quads.iter().any(|quad| {
+            quad.uv.x0 * 256.0 == f64::from(rect.x) && quad.uv.y0 * 256.0 == f64::from(rect.y)
+        }));
+    }
+}
+
+#[test]
+fn color_of_quads() {
+    let mut text = text();
+    let layout = text
+        .new_text_layout("ab")
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .range_attribute(1..2, piet::TextAttribute::TextColor(Color::RED))
+        .build()
+        .unwrap();
+
+    let mut atlas = GlyphAtlas::new(256, 1);
+    let quads = atlas.prepare(&layout, (0.0, 0.0), Color::BLUE).unwrap();
+    let colors = quads.iter().map(|quad| quad.color).collect::<Vec<_>>();
+    assert_eq!(colors, [Color::BLUE, Color::RED]);
+}
+
+#[test]
+fn eviction() {
+    let mut text = text();
+    let mut atlas = GlyphAtlas::new(48, 1);
+
+    let first = layout(&mut text, "A");
+    atlas.prepare(&first, (0.0, 0.0), Color::BLACK).unwrap();
+    let first_key = cache_keys(&first)[0];
+    assert!(atlas.contains(&first_key));
+
+    // Every glyph fits on its own, so the atlas keeps making room.
+    for letter in "BCDEFGHIJKLMNOP".chars() {
+        atlas.next_frame();
+        let layout = layout(&mut text, &letter.to_string());
+        let quads = atlas.prepare(&layout, (0.0, 0.0), Color::BLACK).unwrap();
+        assert_eq!(quads.len(), 1);
+        assert!(atlas.contains(&cache_keys(&layout)[0]));
+    }
+
+    assert_eq!(atlas.page_count(), 1);
+    assert!(!atlas.contains(&first_key));
+
+    // Evicted areas are cleared and uploaded again.
+    let dirty = atlas.take_dirty_rects();
+    assert!(dirty.iter().any(|rect| rect.x == 0 && rect.width == 48));
+}
+
+#[test]
+fn atlas_full() {
+    let mut text = text();
+
+    // Glyphs used in the current frame are never evicted.
+    let mut atlas = GlyphAtlas::new(48, 1);
+    let layout = layout(&mut text, "ABCDEFGHIJKLMNOP");
+    assert!(atlas.prepare(&layout, (0.0, 0.0), Color::BLACK).is_err());
+
+    // Glyphs larger than a page never fit.
+    let mut atlas = GlyphAtlas::new(8, 4);
+    let layout = self::layout(&mut text, "W");
+    assert!(atlas.prepare(&layout, (0.0, 0.0), Color::BLACK).is_err());
+    assert!(atlas.is_empty());
+}
+
+#[test]
+fn subpixel_quads() {
+    let mut text = text();
+    let layout = layout(&mut text, "Sharp");
+    let mut atlas = GlyphAtlas::new(256, 1);
+
+    let quads = atlas.prepare(&layout, (0.0, 0.0), Color::BLACK).unwrap();
+    assert!(quads.iter().all(|quad| !quad.subpixel));
+    let page = atlas.page(0).unwrap();
+    assert!(page.chunks(4).all(|pixel| pixel[..3] == [pixel[3]; 3]));
+    atlas.take_dirty_rects();
+
+    // Changing the antialiasing mode starts over.
+    text.set_antialiasing(Antialiasing::SubpixelRgb);
+    let quads = atlas.prepare(&layout, (0.0, 0.0), Color::BLACK).unwrap();
+    assert!(quads.iter().all(|quad| quad.subpixel));
+    assert_eq!(atlas.take_dirty_rects()[0].width, 256);
+
+    let page = atlas.page(0).unwrap();
+    assert!(page.chunks(4).any(|pixel| pixel[0] != pixel[2]));
+}
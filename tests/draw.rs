@@ -0,0 +1,567 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+use cosmic_text::SwashCache;
+use piet::kurbo::Shape;
+use piet::{
+    Color, FontFamily, FontStyle, FontWeight, Text as _, TextAttribute, TextLayout as _,
+    TextLayoutBuilder as _,
+};
+use piet_cosmic_text::{
+    Antialiasing, DecorationLine, DecorationStyle, RenderOptions, Text, TextLayout,
+};
+
+const WIDTH: usize = 200;
+const HEIGHT: usize = 60;
+
+fn text() -> Text {
+    let text = Text::new();
+    text.wait_for_load_blocking();
+    text
+}
+
+fn layout(text: &mut Text, string: &str) -> TextLayout {
+    text.new_text_layout(string.to_owned())
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .build()
+        .unwrap()
+}
+
+fn draw(layout: &TextLayout, origin: (f64, f64), color: Color) -> Vec<u8> {
+    let mut pixels = vec![0; WIDTH * HEIGHT * 4];
+    layout
+        .draw_into(&mut pixels, WIDTH, HEIGHT, WIDTH * 4, origin, color)
+        .unwrap();
+    pixels
+}
+
+#[test]
+fn draw_matches_swash_cache() {
+    let mut text = text();
+    let layout = layout(&mut text, "Hello, world!");
+    let pixels = draw(&layout, (0.0, 0.0), Color::BLACK);
+
+    // Render the same glyphs the usual way.
+    let mut expected = vec![0u8; WIDTH * HEIGHT];
+    let mut cache = SwashCache::new();
+    text.with_font_system_mut(|system| {
+        for run in layout.layout_runs() {
+            for glyph in run.glyphs {
+                let physical = glyph.physical((0.0, run.line_y), 1.0);
+                let black = cosmic_text::Color::rgb(0, 0, 0);
+                cache.with_pixels(system, physical.cache_key, black, |x, y, color| {
+                    let (x, y) = (physical.x + x, physical.y + y);
+                    if (0..WIDTH as i32).contains(&x) && (0..HEIGHT as i32).contains(&y) {
+                        let alpha = &mut expected[y as usize * WIDTH + x as usize];
+                        *alpha = alpha.saturating_add(color.a());
+                    }
+                });
+            }
+        }
+    })
+    .unwrap();
+
+    let alphas = pixels.chunks(4).map(|pixel| pixel[3]).collect::<Vec<_>>();
+    assert!(alphas.contains(&255));
+    assert_eq!(alphas, expected);
+    assert!(pixels.chunks(4).all(|pixel| pixel[..3] == [0, 0, 0]));
+}
+
+#[test]
+fn draw_blends_colors() {
+    let mut text = text();
+    let red = text
+        .new_text_layout("Red")
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .text_color(Color::rgb8(255, 0, 0))
+        .build()
+        .unwrap();
+
+    // Colors are premultiplied.
+    let pixels = draw(&red, (0.0, 0.0), Color::BLACK);
+    assert!(pixels
+        .chunks(4)
+        .all(|pixel| pixel[0] == pixel[3] && pixel[1..3] == [0, 0]));
+
+    // Drawing over opaque pixels keeps them opaque.
+    let mut pixels = [255u8; WIDTH * HEIGHT * 4];
+    red.draw_into(
+        &mut pixels,
+        WIDTH,
+        HEIGHT,
+        WIDTH * 4,
+        (0.0, 0.0),
+        Color::BLACK,
+    )
+    .unwrap();
+    assert!(pixels
+        .chunks(4)
+        .all(|pixel| pixel[0] == 255 && pixel[3] == 255));
+    assert!(pixels.chunks(4).any(|pixel| pixel[1] == 0));
+
+    // Translucent text is never fully opaque.
+    let faint = draw(
+        &layout(&mut text, "Faint"),
+        (0.0, 0.0),
+        Color::BLACK.with_alpha(0.5),
+    );
+    let max = faint.chunks(4).map(|pixel| pixel[3]).max().unwrap();
+    assert!((127..=128).contains(&max));
+}
+
+#[test]
+fn draw_origin_and_stride() {
+    let mut text = text();
+    let layout = layout(&mut text, "Offset");
+    let pixels = draw(&layout, (0.0, 0.0), Color::BLACK);
+    let moved = draw(&layout, (7.0, 3.0), Color::BLACK);
+
+    for y in 0..HEIGHT - 3 {
+        for x in 0..WIDTH - 7 {
+            let start = (y * WIDTH + x) * 4;
+            let moved_start = ((y + 3) * WIDTH + x + 7) * 4;
+            assert_eq!(
+                pixels[start..start + 4],
+                moved[moved_start..moved_start + 4]
+            );
+        }
+    }
+
+    // Padding at the end of each row is left alone.
+    let stride = WIDTH * 4 + 12;
+    let mut padded = vec![0; stride * HEIGHT];
+    padded
+        .chunks_mut(stride)
+        .for_each(|row| row[WIDTH * 4..].fill(0x55));
+    layout
+        .draw_into(&mut padded, WIDTH, HEIGHT, stride, (0.0, 0.0), Color::BLACK)
+        .unwrap();
+    for (row, padded_row) in pixels.chunks(WIDTH * 4).zip(padded.chunks(stride)) {
+        assert_eq!(row, &padded_row[..WIDTH * 4]);
+        assert!(padded_row[WIDTH * 4..].iter().all(|&byte| byte == 0x55));
+    }
+}
+
+#[test]
+fn draw_decorations() {
+    let mut text = text();
+    let plain = layout(&mut text, "Underlined");
+    let underlined = text
+        .new_text_layout("Underlined")
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .default_attribute(TextAttribute::Underline(true))
+        .build()
+        .unwrap();
+
+    let plain_pixels = draw(&plain, (0.0, 0.0), Color::BLACK);
+    let underlined_pixels = draw(&underlined, (0.0, 0.0), Color::BLACK);
+
+    // Find the rows that only the underlined text covers.
+    let row_coverage = |pixels: &[u8], y: usize| {
+        pixels[y * WIDTH * 4..(y + 1) * WIDTH * 4]
+            .chunks(4)
+            .filter(|pixel| pixel[3] > 0)
+            .count()
+    };
+    let width = underlined.size().width as usize;
+    let baseline = underlined.line_metric(0).unwrap().baseline as usize;
+    let underline_rows = (0..HEIGHT)
+        .filter(|&y| row_coverage(&underlined_pixels, y) > row_coverage(&plain_pixels, y))
+        .collect::<Vec<_>>();
+
+    assert!(!underline_rows.is_empty());
+    assert!(underline_rows.iter().all(|&y| y >= baseline));
+    assert!(underline_rows
+        .iter()
+        .any(|&y| row_coverage(&underlined_pixels, y) + 2 >= width));
+}
+
+#[test]
+fn decoration_styles() {
+    let mut text = text();
+    let layout = text
+        .new_text_layout("Wavy dotted")
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .range_attribute(.., TextAttribute::Underline(true))
+        .range_decoration_style(0..5, DecorationStyle::Wavy)
+        .range_decoration_color(0..5, Color::RED)
+        .range_decoration_style(5.., DecorationStyle::Dotted)
+        .range_overline(5.., true)
+        .build()
+        .unwrap();
+
+    let lines = layout.decoration_lines(Color::BLACK).unwrap();
+    let styles = lines
+        .iter()
+        .map(|line| (line.kind, line.style, line.color))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        styles,
+        [
+            (DecorationLine::Underline, DecorationStyle::Wavy, Color::RED),
+            (
+                DecorationLine::Underline,
+                DecorationStyle::Dotted,
+                Color::BLACK
+            ),
+            (
+                DecorationLine::Overline,
+                DecorationStyle::Dotted,
+                Color::BLACK
+            ),
+        ]
+    );
+
+    // The wave goes from one end of the line to the other.
+    let wavy = lines[0];
+    let wave = wavy.wavy_path().bounding_box();
+    assert_eq!((wave.x0, wave.x1), (wavy.line.p0.x, wavy.line.p1.x));
+    assert!(wave.height() > wavy.thickness);
+    assert!(wavy.rects().is_empty());
+
+    // Dots are as wide as the line is thick.
+    let dotted = lines[1];
+    let dots = dotted.rects();
+    assert!(dots.len() > 1);
+    for dot in &dots[..dots.len() - 1] {
+        assert!((dot.width() - dotted.thickness).abs() < 0.01);
+        assert_eq!(dot.union(dotted.into_rect()), dotted.into_rect());
+    }
+
+    // The overline is above the text.
+    let overline = lines[2];
+    assert!(overline.into_rect().y1 < layout.line_metric(0).unwrap().baseline / 2.0);
+
+    // Both renderers draw the wave in its own color.
+    let pixels = draw(&layout, (0.0, 0.0), Color::BLACK);
+    let red = pixels
+        .chunks(4)
+        .enumerate()
+        .filter(|(_, pixel)| pixel[0] > 128 && pixel[1] == 0 && pixel[3] > 128)
+        .map(|(i, _)| (i / WIDTH) as f64)
+        .collect::<Vec<_>>();
+    assert!(!red.is_empty());
+    let bounds = wavy.bounds();
+    assert!(red
+        .iter()
+        .all(|&y| y >= bounds.y0.floor() && y < bounds.y1.ceil()));
+    let svg = layout.to_svg(Color::BLACK).unwrap();
+    assert_eq!(svg.matches(r##"stroke="#ff0000""##).count(), 1);
+    assert_eq!(svg.matches("<rect ").count(), dots.len() * 2);
+}
+
+#[test]
+fn decoration_line_colors() {
+    let mut text = text();
+    let layout = text
+        .new_text_layout("Red squiggles")
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .default_attribute(TextAttribute::Underline(true))
+        .default_attribute(TextAttribute::Strikethrough(true))
+        .range_attribute(0..3, TextAttribute::TextColor(Color::BLUE))
+        .range_decoration_line_color(.., DecorationLine::Underline, Color::RED)
+        .build()
+        .unwrap();
+
+    // The underline keeps its own color, while the strikethrough follows the text.
+    let lines = layout.decoration_lines(Color::BLACK).unwrap();
+    let colors = lines
+        .iter()
+        .map(|line| (line.kind, line.color))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        colors,
+        [
+            (DecorationLine::Strikethrough, Color::BLUE),
+            (DecorationLine::Underline, Color::RED),
+            (DecorationLine::Strikethrough, Color::BLACK),
+        ]
+    );
+
+    // A color for every line is replaced by the color for one kind of line.
+    let layout = text
+        .new_text_layout("Red squiggles")
+        .font(FontFamily::SANS_SERIF, 24.0)
+        .default_attribute(TextAttribute::Underline(true))
+        .default_attribute(TextAttribute::Strikethrough(true))
+        .range_decoration_color(.., Color::GREEN)
+        .range_decoration_line_color(.., DecorationLine::Underline, Color::RED)
+        .build()
+        .unwrap();
+    let lines = layout.decoration_lines(Color::BLACK).unwrap();
+    let colors = lines
+        .iter()
+        .map(|line| (line.kind, line.color))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        colors,
+        [
+            (DecorationLine::Underline, Color::RED),
+            (DecorationLine::Strikethrough, Color::GREEN),
+        ]
+    );
+}
+
+#[test]
+fn subpixel_antialiasing() {
+    let mut text = text();
+    let layout = layout(&mut text, "Subpixel");
+    let grayscale = draw(&layout, (0.0, 0.0), Color::BLACK);
+    assert_eq!(text.antialiasing(), Antialiasing::Grayscale);
+
+    text.set_antialiasing(Antialiasing::SubpixelRgb);
+    let rgb = draw(&layout, (0.0, 0.0), Color::WHITE);
+    text.set_antialiasing(Antialiasing::SubpixelBgr);
+    let bgr = draw(&layout, (0.0, 0.0), Color::WHITE);
+
+    // Each channel has its own coverage.
+    let fringes = |pixels: &[u8]| {
+        pixels
+            .chunks(4)
+            .filter(|pixel| pixel[0] != pixel[1] || pixel[1] != pixel[2])
+            .count()
+    };
+    assert!(grayscale.chunks(4).all(|pixel| pixel[..3] == [0, 0, 0]));
+    assert!(fringes(&rgb) > 0);
+    assert!(fringes(&bgr) > 0);
+    assert_ne!(rgb, bgr);
+
+    // The coverage of the alpha channel is the largest of the three.
+    assert!(rgb
+        .chunks(4)
+        .all(|pixel| pixel[3] == pixel[0].max(pixel[1]).max(pixel[2])));
+
+    // Red is on the left of every pixel, so it covers more of the left edges of glyphs.
+    let left_edge = |pixels: &[u8], channel: usize| {
+        pixels
+            .chunks(WIDTH * 4)
+            .filter_map(|row| row.chunks(4).position(|pixel| pixel[channel] > 0))
+            .min()
+            .unwrap()
+    };
+    assert!(left_edge(&rgb, 0) <= left_edge(&rgb, 2));
+    assert!(left_edge(&bgr, 2) <= left_edge(&bgr, 0));
+}
+
+#[test]
+fn render_options() {
+    let mut text = text();
+    let coverage = |pixels: &[u8]| {
+        pixels
+            .chunks(4)
+            .map(|pixel| u32::from(pixel[3]))
+            .sum::<u32>()
+    };
+    let partial = |pixels: &[u8]| {
+        pixels
+            .chunks(4)
+            .filter(|pixel| pixel[3] > 0 && pixel[3] < 255)
+            .count()
+    };
+
+    let hinted = layout(&mut text, "Options");
+    let default = draw(&hinted, (0.0, 0.0), Color::BLACK);
+    assert_eq!(text.render_options(), RenderOptions::default());
+
+    // The ink rectangle always covers what is drawn.
+    let mut options = RenderOptions::default();
+    options.hinting = false;
+    text.set_render_options(options);
+    let unhinted = layout(&mut text, "Options");
+    let pixels = draw(&unhinted, (0.0, 0.0), Color::BLACK);
+    assert_ne!(pixels, default);
+
+    for (layout, pixels) in [(&hinted, &default), (&unhinted, &pixels)] {
+        let ink = layout.image_bounds().expand();
+        for (i, pixel) in pixels.chunks(4).enumerate() {
+            if pixel[3] > 0 {
+                let (x, y) = ((i % WIDTH) as f64, (i / WIDTH) as f64);
+                assert!(ink.x0 <= x && ink.y0 <= y && x + 1.0 <= ink.x1 && y + 1.0 <= ink.y1);
+            }
+        }
+    }
+
+    // Gamma makes text heavier, and contrast makes its edges sharper.
+    options.hinting = true;
+    options.gamma = 2.2;
+    text.set_render_options(options);
+    assert!(coverage(&draw(&hinted, (0.0, 0.0), Color::BLACK)) > coverage(&default));
+
+    options.gamma = 1.0;
+    options.contrast = 1.0;
+    text.set_render_options(options);
+    assert!(partial(&draw(&hinted, (0.0, 0.0), Color::BLACK)) < partial(&default));
+}
+
+#[cfg(feature = "embed_fonts")]
+#[test]
+fn synthetic_styles() {
+    // The embedded fonts have no bold or italic faces.
+    let mut text = Text::with_embedded_fonts();
+    let mut layout = |attribute: Option<TextAttribute>| {
+        let mut builder = text
+            .new_text_layout("Styled")
+            .font(FontFamily::SANS_SERIF, 24.0);
+        if let Some(attribute) = attribute {
+            builder = builder.default_attribute(attribute);
+        }
+        builder.build().unwrap()
+    };
+
+    let regular = layout(None);
+    let bold = layout(Some(TextAttribute::Weight(FontWeight::BOLD)));
+    let italic = layout(Some(TextAttribute::Style(FontStyle::Italic)));
+
+    let regular_pixels = draw(&regular, (0.0, 0.0), Color::BLACK);
+    let bold_pixels = draw(&bold, (0.0, 0.0), Color::BLACK);
+    let italic_pixels = draw(&italic, (0.0, 0.0), Color::BLACK);
+
+    // Bold glyphs are heavier.
+    let coverage = |pixels: &[u8]| {
+        pixels
+            .chunks(4)
+            .map(|pixel| u32::from(pixel[3]))
+            .sum::<u32>()
+    };
+    assert!(coverage(&bold_pixels) > coverage(&regular_pixels) * 11 / 10);
+    assert!(bold.image_bounds().width() > regular.image_bounds().width());
+
+    // Italic glyphs lean to the right.
+    assert_ne!(italic_pixels, regular_pixels);
+    assert!(italic.image_bounds().x1 > regular.image_bounds().x1);
+
+    // Outlines are styled the same way.
+    let outline_width = |layout: &TextLayout| {
+        layout
+            .glyph_outlines()
+            .unwrap()
+            .iter()
+            .map(|outline| outline.path.bounding_box().x1)
+            .fold(0.0, f64::max)
+    };
+    assert!(outline_width(&italic) > outline_width(&regular));
+
+    // Synthesis can be turned off.
+    let mut options = RenderOptions::default();
+    options.synthetic_bold = false;
+    options.synthetic_oblique = false;
+    text.set_render_options(options);
+    assert_eq!(draw(&bold, (0.0, 0.0), Color::BLACK), regular_pixels);
+    assert_eq!(draw(&italic, (0.0, 0.0), Color::BLACK), regular_pixels);
+}
+
+#[test]
+fn glyph_outlines() {
+    let mut text = text();
+    let red = Color::rgb8(255, 0, 0);
+    let layout = text
+        .new_text_layout("Outlined text\nin two lines")
+        .font(FontFamily::SERIF, 24.0)
+        .range_attribute(9..13, TextAttribute::TextColor(red))
+        .build()
+        .unwrap();
+    let outlines = layout.glyph_outlines().unwrap();
+
+    // There is an outline for each glyph, with the color of its text.
+    let glyphs = layout
+        .layout_runs()
+        .flat_map(|run| run.glyphs.iter())
+        .count();
+    assert_eq!(outlines.len(), glyphs);
+    for outline in &outlines {
+        let expected = if (9..13).contains(&outline.range.start) {
+            Some(red)
+        } else {
+            None
+        };
+        assert_eq!(outline.color, expected);
+    }
+
+    // The outlines cover the ink of the layout.
+    let bounds = outlines
+        .iter()
+        .filter(|outline| !outline.path.elements().is_empty())
+        .map(|outline| outline.path.bounding_box())
+        .reduce(|a, b| a.union(b))
+        .unwrap();
+    let ink = layout.image_bounds();
+    for (a, b) in [
+        (bounds.x0, ink.x0),
+        (bounds.y0, ink.y0),
+        (bounds.x1, ink.x1),
+        (bounds.y1, ink.y1),
+    ] {
+        assert!((a - b).abs() < 1.0, "{:?} vs {:?}", bounds, ink);
+    }
+
+    // Glyphs on the second line are below the first one.
+    let second_line = layout.line_metric(1).unwrap();
+    let first = outlines.iter().find(|o| o.range.start == 0).unwrap();
+    let last = outlines.iter().find(|o| o.range.start == 14).unwrap();
+    assert_eq!(outlines.last().unwrap().range, 25..26);
+    assert!(first.path.bounding_box().y1 <= second_line.y_offset + 1.0);
+    assert!(last.path.bounding_box().y0 >= second_line.y_offset);
+}
+
+#[cfg(feature = "embed_fonts")]
+#[test]
+fn svg_export() {
+    let svg = |string: &str| {
+        let mut text = Text::with_embedded_fonts();
+        let layout = text
+            .new_text_layout(string.to_owned())
+            .font(FontFamily::SANS_SERIF, 24.0)
+            .range_attribute(0..4, TextAttribute::TextColor(Color::rgb8(0, 128, 255)))
+            .range_attribute(5.., TextAttribute::Underline(true))
+            .build()
+            .unwrap();
+        let svg = layout.to_svg(Color::BLACK.with_alpha(0.5)).unwrap();
+        (layout, svg)
+    };
+
+    let (layout, document) = svg("Blue text");
+
+    // There is a path for every glyph with an outline, and a rectangle for the underline.
+    assert!(document.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+    assert!(document.ends_with("</svg>\n"));
+    assert_eq!(document.matches("<path ").count(), 8);
+    assert_eq!(document.matches(r##"fill="#0080ff""##).count(), 4);
+    assert_eq!(
+        document
+            .matches(r##"fill="#000000" fill-opacity="0.5""##)
+            .count(),
+        5
+    );
+    assert_eq!(document.matches("<rect ").count(), 1);
+
+    // The view box covers the layout and the underline below it.
+    let size = layout.size();
+    let view_box = document
+        .split(r#"viewBox=""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .split(' ')
+        .map(|n| n.parse::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(view_box[..3], [0.0, 0.0, size.width.ceil()]);
+    assert!(view_box[3] >= size.height);
+
+    // The embedded fonts give the same document every time.
+    assert_eq!(svg("Blue text").1, document);
+}
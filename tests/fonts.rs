@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+use piet::{FontFamily, Text as _};
+use piet_cosmic_text::Text;
+
+const SANS: &[u8] = include_bytes!("../fonts/ttf/DejaVuSans.ttf");
+const SERIF: &[u8] = include_bytes!("../fonts/ttf/DejaVuSerif.ttf");
+const VARIABLE: &[u8] = include_bytes!("fonts/Inconsolata-variable.ttf");
+
+/// Pack several TrueType fonts into a single TrueType collection.
+fn collection(fonts: &[&[u8]]) -> Vec<u8> {
+    let read_u16 = |data: &[u8], at: usize| u16::from_be_bytes([data[at], data[at + 1]]) as usize;
+    let read_u32 = |data: &[u8], at: usize| {
+        u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as usize
+    };
+
+    // The header, followed by the table directory of every font.
+    let header_len = 12 + 4 * fonts.len();
+    let directories_len: usize = fonts.iter().map(|font| 12 + 16 * read_u16(font, 4)).sum();
+
+    let mut out = b"ttcf\x00\x01\x00\x00".to_vec();
+    out.extend_from_slice(&(fonts.len() as u32).to_be_bytes());
+    out.resize(header_len + directories_len, 0);
+
+    let mut directory = header_len;
+    for (i, font) in fonts.iter().enumerate() {
+        out[12 + 4 * i..16 + 4 * i].copy_from_slice(&(directory as u32).to_be_bytes());
+
+        let num_tables = read_u16(font, 4);
+        out[directory..directory + 12].copy_from_slice(&font[..12]);
+        for table in 0..num_tables {
+            let record = 12 + 16 * table;
+            let (offset, len) = (read_u32(font, record + 8), read_u32(font, record + 12));
+
+            // Copy the table over and point the record at its new location.
+            while out.len() % 4 != 0 {
+                out.push(0);
+            }
+            let new_offset = out.len();
+            out.extend_from_slice(&font[offset..offset + len]);
+
+            let new_record = directory + record;
+            out[new_record..new_record + 8].copy_from_slice(&font[record..record + 8]);
+            out[new_record + 8..new_record + 12]
+                .copy_from_slice(&(new_offset as u32).to_be_bytes());
+            out[new_record + 12..new_record + 16].copy_from_slice(&(len as u32).to_be_bytes());
+        }
+
+        directory += 12 + 16 * num_tables;
+    }
+
+    out
+}
+
+#[test]
+fn load_single_font() {
+    let text = Text::new();
+    text.wait_for_load_blocking();
+
+    let faces = text.load_font_collection(SERIF).unwrap();
+    assert_eq!(faces.len(), 1);
+    assert_eq!(faces[0].family().name(), "DejaVu Serif");
+}
+
+#[test]
+fn load_every_face_in_collection() {
+    let mut text = Text::new();
+    text.wait_for_load_blocking();
+    let data = collection(&[SANS, SERIF]);
+
+    let faces = text.load_font_collection(&data).unwrap();
+    let families = faces
+        .iter()
+        .map(|face| face.family().name())
+        .collect::<Vec<_>>();
+    assert_eq!(families, ["DejaVu Sans", "DejaVu Serif"]);
+
+    // Every face is registered with the font system.
+    text.with_font_system_mut(|system| {
+        for face in &faces {
+            assert!(system.db().face(face.id()).is_some());
+        }
+    })
+    .unwrap();
+
+    // `load_font` reports the family of the first face.
+    let family = text.load_font(&data).unwrap();
+    assert_eq!(family, FontFamily::new_unchecked("DejaVu Sans"));
+}
+
+#[test]
+fn load_invalid_font() {
+    let text = Text::new();
+    text.wait_for_load_blocking();
+
+    assert!(text.load_font_collection(b"not a font").is_err());
+}
+
+#[cfg(feature = "embed_fonts")]
+#[test]
+fn missing_styles_are_synthesized() {
+    use piet::{FontStyle, FontWeight, TextAttribute, TextLayoutBuilder as _};
+    use piet_cosmic_text::Metadata;
+
+    // The embedded fonts only have regular faces.
+    let mut text = Text::with_embedded_fonts();
+    let layout = text
+        .new_text_layout("Bold \u{4e00} italic")
+        .font(FontFamily::SERIF, 24.0)
+        .default_attribute(TextAttribute::Weight(FontWeight::BOLD))
+        .range_attribute(9.., TextAttribute::Style(FontStyle::Italic))
+        .build()
+        .unwrap();
+
+    let glyphs = layout
+        .layout_runs()
+        .flat_map(|run| run.glyphs.iter())
+        .collect::<Vec<_>>();
+    assert!(glyphs.iter().any(|glyph| glyph.glyph_id == 0));
+
+    for glyph in glyphs {
+        // The family is kept, rather than falling back to another family.
+        if glyph.glyph_id != 0 {
+            let family = text
+                .with_font_system_mut(|system| {
+                    system.db().face(glyph.font_id).unwrap().families[0]
+                        .0
+                        .clone()
+                })
+                .unwrap();
+            assert_eq!(family, "DejaVu Serif");
+        }
+
+        // The requested style is kept, even for text no font could render.
+        let metadata = Metadata::from_raw(glyph.metadata);
+        assert_eq!(metadata.boldness(), FontWeight::BOLD);
+        assert_eq!(metadata.italic(), glyph.start >= 9);
+    }
+}
+
+#[test]
+fn variable_font_axes() {
+    use piet::{FontWeight, TextLayout as _, TextLayoutBuilder as _};
+    use piet_cosmic_text::{FontVariation, RenderOptions};
+
+    let mut text = Text::new();
+    text.wait_for_load_blocking();
+    let family = text.load_font(VARIABLE).unwrap();
+    assert_eq!(family.name(), "Inconsolata");
+
+    let mut layout = |weight: FontWeight, variations: &[FontVariation]| {
+        text.new_text_layout("Variable")
+            .font(family.clone(), 24.0)
+            .default_attribute(weight)
+            .range_variations(.., variations.iter().copied())
+            .build()
+            .unwrap()
+    };
+    let ink = |layout: &piet_cosmic_text::TextLayout| {
+        let mut pixels = vec![0; 200 * 40 * 4];
+        layout
+            .draw_into(
+                &mut pixels,
+                200,
+                40,
+                200 * 4,
+                (0.0, 0.0),
+                piet::Color::BLACK,
+            )
+            .unwrap();
+        pixels.iter().map(|&c| u32::from(c)).sum::<u32>()
+    };
+
+    // Intermediate weights set the weight axis instead of snapping to a face.
+    let regular = layout(FontWeight::REGULAR, &[]);
+    let medium = layout(FontWeight::MEDIUM, &[]);
+    let bold = layout(FontWeight::BOLD, &[]);
+    assert!(ink(&regular) < ink(&medium));
+    assert!(ink(&medium) < ink(&bold));
+
+    // An explicit value replaces the one from the weight.
+    let light = layout(FontWeight::BOLD, &[FontVariation::new(*b"wght", 300.0)]);
+    assert!(ink(&light) < ink(&regular));
+
+    // Other axes change the advances of glyphs as well as their ink.
+    let wide = layout(FontWeight::REGULAR, &[FontVariation::new(*b"wdth", 150.0)]);
+    assert!(wide.size().width > regular.size().width * 1.2);
+    assert!(wide.image_bounds().width() > regular.image_bounds().width() * 1.2);
+
+    // Fonts with a weight axis are never emboldened.
+    let synthetic = ink(&bold);
+    let mut options = RenderOptions::default();
+    options.synthetic_bold = false;
+    text.set_render_options(options);
+    assert_eq!(ink(&bold), synthetic);
+}
+
+#[test]
+fn decoration_metrics() {
+    use piet::{Color, TextAttribute, TextLayout as _, TextLayoutBuilder as _};
+    use piet_cosmic_text::DecorationMetrics;
+
+    let mut text = Text::new();
+    text.wait_for_load_blocking();
+    let sans = text.load_font(SANS).unwrap();
+    let mono = text.load_font(VARIABLE).unwrap();
+
+    let layout = text
+        .new_text_layout("Sans Mono")
+        .font(sans, 48.0)
+        .default_attribute(TextAttribute::Underline(true))
+        .range_attribute(5.., TextAttribute::FontFamily(mono))
+        .build()
+        .unwrap();
+    let lines = layout.decoration_lines(Color::BLACK).unwrap();
+    let baseline = layout.line_metric(0).unwrap().baseline;
+
+    // Each font places the underline itself.
+    assert_eq!(lines.len(), 2);
+    let run = layout.layout_runs().next().unwrap();
+    for (line, glyph) in lines.iter().zip([&run.glyphs[0], &run.glyphs[5]]) {
+        let (metrics, fallback) = text
+            .with_font_system_mut(|system| {
+                let font = system.get_font(glyph.font_id).unwrap();
+                let font_metrics = font.as_swash().metrics(&[]);
+                let units_per_em = f32::from(font_metrics.units_per_em);
+                let fallback = DecorationMetrics::fallback(
+                    font_metrics.ascent / units_per_em,
+                    font_metrics.descent / units_per_em,
+                    glyph.font_size,
+                );
+
+                (DecorationMetrics::new(&font, glyph.font_size), fallback)
+            })
+            .unwrap();
+        assert_ne!(metrics, fallback);
+        assert!(fallback.underline_offset > 0.0);
+        assert!(fallback.overline_offset < fallback.strikethrough_offset);
+        assert!(metrics.underline_offset > 0.0);
+        assert!(metrics.strikethrough_offset < 0.0);
+
+        assert!((line.thickness - f64::from(metrics.thickness)).abs() < 0.001);
+        assert!((line.line.p0.y - baseline - f64::from(metrics.underline_offset)).abs() < 0.001);
+    }
+    assert_ne!(lines[0].thickness, lines[1].thickness);
+}
@@ -0,0 +1,980 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-cosmic-text`.
+//
+// `piet-cosmic-text` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-cosmic-text` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-cosmic-text`. If not, see <https://www.gnu.org/licenses/>.
+
+use piet::kurbo::Point;
+use piet::{
+    Color, FontFamily, FontWeight, Text as _, TextAttribute, TextLayout as _,
+    TextLayoutBuilder as _,
+};
+use piet_cosmic_text::{
+    FontFeature, LineHeight, ShapingMode, Text, TextLayout, TextOverflow, WrapMode,
+};
+
+fn text() -> Text {
+    let text = Text::new();
+    text.wait_for_load_blocking();
+    text
+}
+
+#[test]
+fn range_font_size() {
+    let mut text = text();
+
+    let uniform = text
+        .new_text_layout("Hello world")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    let mixed = text
+        .new_text_layout("Hello world")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .range_attribute(6.., TextAttribute::FontSize(24.0))
+        .build()
+        .unwrap();
+
+    // Glyphs in the range are scaled up, the others are left alone.
+    let sizes = mixed
+        .layout_runs()
+        .flat_map(|run| run.glyphs.iter())
+        .map(|glyph| (glyph.start, glyph.font_size))
+        .collect::<Vec<_>>();
+    assert!(sizes.iter().all(|&(start, size)| if start < 6 {
+        size == 16.0
+    } else {
+        size == 32.0
+    }));
+
+    // The line, the layout and the ink all get bigger.
+    assert_eq!(uniform.line_metric(0).unwrap().height, 16.0);
+    assert_eq!(mixed.line_metric(0).unwrap().height, 32.0);
+    assert!(mixed.size().width > uniform.size().width);
+    assert!(mixed.size().height > uniform.size().height);
+    assert!(mixed.image_bounds().height() > uniform.image_bounds().height());
+}
+
+#[test]
+fn trailing_whitespace() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello   \nworld")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+
+    // The spaces and the newline are trailing whitespace, the last line has none.
+    let first = layout.line_metric(0).unwrap();
+    assert_eq!(first.trailing_whitespace, 4);
+    assert_eq!(layout.line_metric(1).unwrap().trailing_whitespace, 0);
+
+    // The width of the spaces is included in the trailing whitespace width, but not the size.
+    let hello = text
+        .new_text_layout("Hello")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    let world = text
+        .new_text_layout("world")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    assert_eq!(
+        layout.size().width,
+        hello.size().width.max(world.size().width)
+    );
+    assert!(layout.trailing_whitespace_width() > hello.size().width);
+    assert_eq!(hello.trailing_whitespace_width(), hello.size().width);
+}
+
+#[test]
+fn trailing_whitespace_wrapped() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello world")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(40.0)
+        .build()
+        .unwrap();
+
+    // The space hangs off of the end of the first line.
+    assert_eq!(layout.line_count(), 2);
+    assert_eq!(layout.line_text(0), Some("Hello "));
+    assert_eq!(layout.line_metric(0).unwrap().trailing_whitespace, 1);
+    assert_eq!(layout.line_metric(1).unwrap().trailing_whitespace, 0);
+}
+
+#[test]
+fn text_position_end_and_empty_lines() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello\n\nworld\n")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    assert_eq!(layout.line_count(), 4);
+
+    // The newline is at the end of the first line.
+    let end_of_hello = layout.hit_test_text_position(5);
+    assert_eq!(end_of_hello.line, 0);
+    assert!(end_of_hello.point.x > 0.0);
+
+    // The empty line and the line after the trailing newline.
+    let empty = layout.hit_test_text_position(6);
+    assert_eq!(empty.line, 1);
+    assert_eq!(empty.point.x, 0.0);
+    assert!(empty.point.y > end_of_hello.point.y);
+
+    let end = layout.hit_test_text_position(13);
+    assert_eq!(end.line, 3);
+    assert_eq!(end.point.x, 0.0);
+
+    // The end of the last word is on its trailing edge.
+    let end_of_world = layout.hit_test_text_position(12);
+    assert_eq!(end_of_world.line, 2);
+    assert!((end_of_world.point.x - end_of_hello.point.x).abs() < 10.0);
+
+    // An empty layout still has a line.
+    let empty = text.new_text_layout("").build().unwrap();
+    assert_eq!(empty.line_count(), 1);
+    assert_eq!(empty.hit_test_text_position(0).point.x, 0.0);
+}
+
+#[test]
+fn text_position_is_monotonic() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("office affine")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+
+    // Even inside of ligatures, carets move to the right.
+    let xs = (0..=layout.text().len())
+        .map(|idx| layout.hit_test_text_position(idx).point.x)
+        .collect::<Vec<_>>();
+    assert!(xs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", xs);
+    assert!((xs.last().unwrap() - layout.size().width).abs() < 0.01);
+}
+
+#[test]
+fn text_position_rtl() {
+    let mut text = text();
+
+    // Hebrew is laid out from right to left.
+    let layout = text
+        .new_text_layout("שלום")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    let width = layout.size().width;
+
+    let start = layout.hit_test_text_position(0).point.x;
+    let middle = layout.hit_test_text_position(4).point.x;
+    let end = layout.hit_test_text_position(8).point.x;
+    assert!((start - width).abs() < 0.01, "{} {}", start, width);
+    assert!(start > middle && middle > end);
+    assert!(end.abs() < 0.01);
+}
+
+#[test]
+fn hit_test_point_lines() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello\nworld")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    let first = layout.line_metric(0).unwrap();
+    let second = layout.line_metric(1).unwrap();
+    let middle = |metric: &piet::LineMetric| metric.y_offset + metric.height / 2.0;
+
+    // A point on the leading half of the first glyph hits its start.
+    let hit = layout.hit_test_point(Point::new(1.0, middle(&first)));
+    assert_eq!(hit.idx, 0);
+    assert!(hit.is_inside);
+
+    // A point on the second line hits text on the second line.
+    let hit = layout.hit_test_point(Point::new(1.0, middle(&second)));
+    assert_eq!(hit.idx, 6);
+    assert!(hit.is_inside);
+
+    // Points past the end of a line snap to the end of the line.
+    let hit = layout.hit_test_point(Point::new(1000.0, middle(&first)));
+    assert_eq!(hit.idx, 5);
+    assert!(!hit.is_inside);
+
+    // Points above and below the text snap to the first and last lines.
+    let hit = layout.hit_test_point(Point::new(1.0, -100.0));
+    assert_eq!(hit.idx, 0);
+    assert!(!hit.is_inside);
+    let hit = layout.hit_test_point(Point::new(1000.0, 1000.0));
+    assert_eq!(hit.idx, 11);
+    assert!(!hit.is_inside);
+}
+
+#[test]
+fn hit_test_point_round_trip() {
+    let mut text = text();
+
+    for string in ["office affine", "שלום עולם", "abc שלום def"] {
+        let layout = text
+            .new_text_layout(string)
+            .font(FontFamily::SANS_SERIF, 12.0)
+            .build()
+            .unwrap();
+
+        // Hitting the caret position of a grapheme boundary gives back that boundary.
+        for idx in (0..string.len()).filter(|&idx| string.is_char_boundary(idx)) {
+            let position = layout.hit_test_text_position(idx);
+            let hit = layout.hit_test_point(position.point);
+            let position_again = layout.hit_test_text_position(hit.idx);
+            assert!(
+                (position.point.x - position_again.point.x).abs() < 0.01,
+                "{:?} at {}: {} != {}",
+                string,
+                idx,
+                position.point.x,
+                position_again.point.x
+            );
+        }
+    }
+}
+
+#[test]
+fn hit_test_point_while_borrowed() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+
+    // Hit testing doesn't need the font system.
+    text.with_font_system_mut(|_| {
+        let hit = layout.hit_test_point(Point::new(1000.0, 1.0));
+        assert_eq!(hit.idx, 5);
+    })
+    .unwrap();
+}
+
+#[test]
+fn range_rects() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello\nworld")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+
+    // A selection across both lines has a rectangle on each line.
+    let rects = layout.rects_for_range(3..8);
+    assert_eq!(rects.len(), 2);
+    let (first, second) = (rects[0], rects[1]);
+    assert_eq!(first.x0, layout.hit_test_text_position(3).point.x);
+    assert_eq!(first.x1, layout.hit_test_text_position(5).point.x);
+    assert_eq!(second.x0, 0.0);
+    assert_eq!(second.x1, layout.hit_test_text_position(8).point.x);
+    assert_eq!(first.y1, second.y0);
+
+    assert!(layout.rects_for_range(2..2).is_empty());
+}
+
+#[test]
+fn range_rects_bidi() {
+    let mut text = text();
+
+    // The Hebrew word is right-to-left in the middle of left-to-right text.
+    let string = "abc שלום def";
+    let layout = text
+        .new_text_layout(string)
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+
+    let rects = layout.range_rects(..);
+    assert_eq!(
+        rects.iter().map(|r| r.rtl).collect::<Vec<_>>(),
+        [false, true, false]
+    );
+    assert!(rects
+        .windows(2)
+        .all(|pair| pair[0].rect.x1 == pair[1].rect.x0));
+
+    // Selecting across the direction change covers the end of the Hebrew word, visually.
+    let hebrew = string.find('ש').unwrap();
+    let rects = layout.range_rects(0..hebrew + 2);
+    assert_eq!(rects.len(), 2);
+    assert!(!rects[0].rtl && rects[1].rtl);
+    let hebrew_rect = rects[1].rect;
+    let start = layout.hit_test_text_position(hebrew).point.x;
+    assert!((hebrew_rect.x1 - start).abs() < 0.01);
+    assert!(hebrew_rect.x0 > rects[0].rect.x1);
+}
+
+#[test]
+fn cursor_graphemes_and_words() {
+    let mut text = text();
+
+    // "é" is two code points, the flag is two regional indicators.
+    let string = "e\u{301}🇺🇸 one, two\r\nthree";
+    let layout = text.new_text_layout(string).build().unwrap();
+
+    assert_eq!(layout.next_grapheme(0), 3);
+    assert_eq!(layout.next_grapheme(3), 11);
+    assert_eq!(layout.previous_grapheme(11), 3);
+    assert_eq!(layout.previous_grapheme(0), 0);
+    assert_eq!(layout.next_grapheme(string.len()), string.len());
+
+    // "\r\n" is a single grapheme.
+    let crlf = string.find('\r').unwrap();
+    assert_eq!(layout.next_grapheme(crlf), crlf + 2);
+
+    // Words skip whitespace, but punctuation is a word of its own. Positions inside a word move
+    // to its ends.
+    let one = string.find("one").unwrap();
+    let two = string.find("two").unwrap();
+    assert_eq!(layout.next_word(one), one + 3);
+    assert_eq!(layout.next_word(one + 1), one + 3);
+    assert_eq!(layout.previous_word(one + 2), one);
+    assert_eq!(layout.next_word(one + 3), one + 4);
+    assert_eq!(layout.next_word(one + 4), two + 3);
+    assert_eq!(layout.previous_word(two + 3), two);
+    assert_eq!(layout.previous_word(two), one + 3);
+    assert_eq!(layout.next_word(string.len()), string.len());
+    assert_eq!(layout.previous_word(0), 0);
+}
+
+#[test]
+fn cursor_lines() {
+    let mut text = text();
+
+    let layout = text
+        .new_text_layout("Hello world\nab\nlonger line")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(40.0)
+        .build()
+        .unwrap();
+    assert_eq!(layout.line_text(0), Some("Hello "));
+    assert_eq!(layout.line_text(1), Some("world\n"));
+
+    assert_eq!(layout.line_start(8), 6);
+    assert_eq!(layout.line_end(8), 11);
+    assert_eq!(layout.line_end(2), 5);
+    assert_eq!(layout.line_start(13), 12);
+
+    // Moving down through a short line keeps the original X coordinate.
+    let x = layout.hit_test_text_position(10).point.x;
+    let down = layout.line_down(10, None);
+    assert_eq!(down, 14);
+    let down = layout.line_down(down, Some(x));
+    assert!(layout.hit_test_text_position(down).point.x > 20.0);
+    assert_eq!(layout.line_up(layout.line_up(down, Some(x)), Some(x)), 10);
+
+    assert_eq!(layout.line_up(3, None), 0);
+    assert_eq!(
+        layout.line_down(layout.text().len() - 1, None),
+        layout.text().len()
+    );
+}
+
+#[test]
+fn cursor_visual_movement() {
+    let mut text = text();
+
+    // Left-to-right text moves forwards when moving right.
+    let layout = text.new_text_layout("ab\ncd").build().unwrap();
+    assert_eq!(layout.move_right(0), 1);
+    assert_eq!(layout.move_right(2), 3);
+    assert_eq!(layout.move_left(3), 2);
+    assert_eq!(layout.move_left(0), 0);
+
+    // Right-to-left text moves backwards when moving right.
+    let layout = text.new_text_layout("שלום").build().unwrap();
+    assert_eq!(layout.move_left(0), 2);
+    assert_eq!(layout.move_left(2), 4);
+    assert_eq!(layout.move_right(4), 2);
+
+    // Moving across a direction change follows the visual order.
+    let string = "ab שלום";
+    let layout = text.new_text_layout(string).build().unwrap();
+    let mut idx = 0;
+    let mut xs = vec![layout.hit_test_text_position(idx).point.x];
+    for _ in 0..6 {
+        idx = layout.move_right(idx);
+        xs.push(layout.hit_test_text_position(idx).point.x);
+    }
+    assert!(xs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", xs);
+}
+
+#[test]
+fn cursor_visual_movement_long_line() {
+    let mut text = text();
+
+    // Moving along a long line goes one position at a time, without slowing to a crawl.
+    let string = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+    let layout = text.new_text_layout(string.clone()).build().unwrap();
+    assert_eq!(layout.line_count(), 1);
+
+    for idx in (0..string.len()).step_by(97) {
+        assert_eq!(layout.move_right(idx), idx + 1);
+        assert_eq!(layout.move_left(idx + 1), idx);
+    }
+    assert_eq!(layout.move_right(string.len() - 1), string.len());
+    assert_eq!(layout.move_left(string.len()), string.len() - 1);
+}
+
+#[test]
+fn line_height() {
+    let mut text = text();
+    let build = |text: &mut Text, line_height: Option<LineHeight>| {
+        let mut builder = text
+            .new_text_layout("Hello\nworld")
+            .font(FontFamily::SANS_SERIF, 12.0);
+        if let Some(line_height) = line_height {
+            builder = builder.line_height(line_height);
+        }
+        builder.build().unwrap()
+    };
+
+    let normal = build(&mut text, None);
+    let relative = build(&mut text, Some(LineHeight::Relative(1.5)));
+    let absolute = build(&mut text, Some(LineHeight::Absolute(30.0)));
+
+    // 12 points is 16 pixels.
+    let metric = relative.line_metric(1).unwrap();
+    assert_eq!(metric.height, 24.0);
+    assert_eq!(metric.y_offset, 24.0);
+    assert_eq!(absolute.line_metric(1).unwrap().height, 40.0);
+    assert_eq!(relative.size().height, 48.0);
+    assert_eq!(absolute.size().height, 80.0);
+
+    // The extra space is split above and below the text.
+    let normal_metric = normal.line_metric(0).unwrap();
+    let relative_metric = relative.line_metric(0).unwrap();
+    assert_eq!(relative_metric.baseline, normal_metric.baseline + 4.0);
+
+    // The default can be set on the `Text`.
+    let old = text.set_line_height(LineHeight::Relative(2.0));
+    assert_eq!(old, LineHeight::Relative(1.0));
+    assert_eq!(build(&mut text, None).line_metric(0).unwrap().height, 32.0);
+    assert_eq!(
+        build(&mut text, Some(LineHeight::Relative(1.0)))
+            .line_metric(0)
+            .unwrap()
+            .height,
+        16.0
+    );
+}
+
+#[test]
+fn wrap_modes() {
+    let mut text = text();
+    let string = "Supercalifragilisticexpialidocious is long";
+    let build = |text: &mut Text, wrap_mode| {
+        text.new_text_layout(string)
+            .font(FontFamily::SANS_SERIF, 12.0)
+            .max_width(100.0)
+            .wrap_mode(wrap_mode)
+            .build()
+            .unwrap()
+    };
+
+    // Word wrapping leaves the long word on its own line, sticking out.
+    let word = build(&mut text, WrapMode::Word);
+    assert_eq!(
+        word.line_text(0),
+        Some("Supercalifragilisticexpialidocious ")
+    );
+    assert!(word.size().width > 100.0);
+
+    // Glyph wrapping breaks the long word up.
+    let glyph = build(&mut text, WrapMode::Glyph);
+    assert!(glyph.line_count() > word.line_count());
+    assert!(glyph.size().width <= 100.0);
+
+    // No wrapping keeps everything on one line.
+    let none = build(&mut text, WrapMode::None);
+    assert_eq!(none.line_count(), 1);
+    assert!(none.size().width > word.size().width);
+}
+
+#[test]
+fn ellipsis_overflow() {
+    let mut text = text();
+    let string = "Hello world, this is a long line";
+
+    let layout = text
+        .new_text_layout(string)
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(100.0)
+        .wrap_mode(WrapMode::None)
+        .text_overflow(TextOverflow::Ellipsis)
+        .build()
+        .unwrap();
+
+    assert_eq!(layout.line_count(), 1);
+    assert_eq!(layout.text(), string);
+    assert!(layout.size().width <= 100.0);
+    assert!(layout.image_bounds().x1 <= 100.0);
+
+    // The last glyph is the ellipsis, which covers the cut off text.
+    let runs = layout.layout_runs().collect::<Vec<_>>();
+    let last = runs[0].glyphs.last().unwrap();
+    assert_eq!(last.end, string.len());
+    assert!(last.start > 0 && last.start < string.len());
+
+    // Text that fits is left alone.
+    let short = text
+        .new_text_layout("Hello")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(100.0)
+        .text_overflow(TextOverflow::Ellipsis)
+        .build()
+        .unwrap();
+    let glyphs = short
+        .layout_runs()
+        .map(|run| run.glyphs.len())
+        .sum::<usize>();
+    assert_eq!(glyphs, 5);
+}
+
+#[test]
+fn max_lines() {
+    let mut text = text();
+    let string = "one two three four five six seven eight nine ten";
+    let build = |text: &mut Text, overflow| {
+        text.new_text_layout(string)
+            .font(FontFamily::SANS_SERIF, 12.0)
+            .max_width(80.0)
+            .max_lines(2)
+            .text_overflow(overflow)
+            .build()
+            .unwrap()
+    };
+
+    let full = text
+        .new_text_layout(string)
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(80.0)
+        .build()
+        .unwrap();
+    assert!(full.line_count() > 2);
+    assert!(!full.is_truncated());
+
+    // Without an ellipsis, the lines are just cut off.
+    let clipped = build(&mut text, TextOverflow::Visible);
+    assert_eq!(clipped.line_count(), 2);
+    assert_eq!(clipped.text(), string);
+    assert_eq!(clipped.line_text(0), full.line_text(0));
+    let end = clipped.line_metric(0).unwrap().end_offset + full.line_text(1).unwrap().len();
+    assert_eq!(clipped.truncated_ranges().len(), 1);
+    assert_eq!(clipped.truncated_ranges()[0], end..string.len());
+
+    // The last line covers the hidden text, even in later paragraphs, but its trailing
+    // whitespace doesn't.
+    let paragraphs = format!("{}\nmore text", string);
+    let clipped = text
+        .new_text_layout(paragraphs.clone())
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(80.0)
+        .max_lines(2)
+        .build()
+        .unwrap();
+    let last = clipped.line_metric(1).unwrap();
+    let visible = full.line_text(1).unwrap();
+    assert_eq!(last.end_offset, paragraphs.len());
+    assert_eq!(
+        last.trailing_whitespace,
+        visible.len() - visible.trim_end().len()
+    );
+    assert_eq!(clipped.truncated_ranges()[0], end..paragraphs.len());
+
+    // Paragraph separators after the last line are cut off along with the paragraphs.
+    let separated = text
+        .new_text_layout("one\ntwo\nthree")
+        .max_lines(1)
+        .build()
+        .unwrap();
+    assert_eq!(separated.line_metric(0).unwrap().trailing_whitespace, 0);
+    assert_eq!(separated.truncated_ranges().len(), 1);
+    assert_eq!(separated.truncated_ranges()[0], 3..13);
+    assert_eq!(
+        clipped.size().height,
+        full.size().height * 2.0 / full.line_count() as f64
+    );
+    assert!(clipped.image_bounds().y1 < full.image_bounds().y1);
+
+    // With an ellipsis, the last line ends with one.
+    let ellipsis = build(&mut text, TextOverflow::Ellipsis);
+    assert_eq!(ellipsis.line_count(), 2);
+    assert!(ellipsis.size().width <= 80.0);
+    let range = ellipsis.truncated_ranges()[0].clone();
+    assert_eq!(range.end, string.len());
+    assert!(range.start <= end);
+
+    let last_run = ellipsis.layout_runs().next_back().unwrap();
+    let marker = last_run.glyphs.last().unwrap();
+    assert_eq!(marker.start, range.start);
+
+    // Hit testing the ellipsis hits the text that was cut off.
+    let point = ellipsis.hit_test_text_position(range.start).point;
+    assert_eq!(ellipsis.hit_test_point(point).idx, range.start);
+}
+
+#[test]
+fn custom_ellipsis() {
+    let mut text = text();
+    let build = |text: &mut Text, ellipsis: &str| {
+        text.new_text_layout("Hello world, this is a long line")
+            .font(FontFamily::SANS_SERIF, 12.0)
+            .max_width(100.0)
+            .max_lines(1)
+            .text_overflow(TextOverflow::Ellipsis)
+            .ellipsis(ellipsis)
+            .range_attribute(0.., TextAttribute::FontSize(24.0))
+            .build()
+            .unwrap()
+    };
+
+    // The marker is shaped with the attributes of the text it replaces.
+    let layout = build(&mut text, "...");
+    let glyphs = layout.layout_runs().next().unwrap().glyphs;
+    let dots = &glyphs[glyphs.len() - 3..];
+    assert!(dots.iter().all(|glyph| glyph.font_size == 32.0));
+    assert!(dots.iter().all(|glyph| glyph.glyph_id == dots[0].glyph_id));
+
+    let layout = build(&mut text, "");
+    assert!(layout.size().width <= 100.0);
+    assert!(layout.is_truncated());
+
+    // Without a marker, wrapped lines still cover all of the text that was cut off of them.
+    let string = "Supercalifragilistic is long";
+    let layout = text
+        .new_text_layout(string)
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(50.0)
+        .text_overflow(TextOverflow::Ellipsis)
+        .ellipsis("")
+        .build()
+        .unwrap();
+    let first = layout.line_metric(0).unwrap();
+    assert_eq!(layout.line_text(0), Some("Supercalifragilistic "));
+    assert_eq!(layout.truncated_ranges().len(), 1);
+    let range = layout.truncated_ranges()[0].clone();
+    assert!(range.start > 0);
+    assert_eq!(range.end, first.end_offset);
+    assert_eq!(
+        layout.line_metric(1).unwrap().start_offset,
+        first.end_offset
+    );
+}
+
+/// Describe the glyphs of a layout and where they are drawn.
+fn glyphs(layout: &TextLayout) -> Vec<String> {
+    layout
+        .layout_runs()
+        .flat_map(|run| {
+            let line_y = run.line_y;
+            run.glyphs
+                .iter()
+                .map(move |glyph| format!("{:?} at {}", glyph, line_y))
+        })
+        .collect()
+}
+
+#[test]
+fn shaping_modes() {
+    let mut text = text();
+
+    let cases = [
+        (FontFamily::MONOSPACE, "Hello, world! 123"),
+        (FontFamily::MONOSPACE, "Ελληνικά и кириллица"),
+        (FontFamily::SANS_SERIF, "AVATAR office"),
+        (FontFamily::SERIF, "Wavy tea"),
+    ];
+
+    for (family, string) in cases {
+        let mut layouts = [
+            ShapingMode::Automatic,
+            ShapingMode::Basic,
+            ShapingMode::Advanced,
+        ]
+        .map(|mode| {
+            text.new_text_layout(string)
+                .font(family.clone(), 12.0)
+                .max_width(60.0)
+                .range_attribute(2..7, TextAttribute::FontSize(18.0))
+                .range_attribute(4..9, TextAttribute::Underline(true))
+                .shaping_mode(mode)
+                .build()
+                .unwrap()
+        })
+        .into_iter();
+        let (automatic, basic, advanced) = (
+            layouts.next().unwrap(),
+            layouts.next().unwrap(),
+            layouts.next().unwrap(),
+        );
+
+        assert_eq!(basic.shaping_mode(), ShapingMode::Basic);
+        assert_eq!(advanced.shaping_mode(), ShapingMode::Advanced);
+
+        // Whichever shaper is picked, the result is the same as advanced shaping.
+        assert_eq!(glyphs(&automatic), glyphs(&advanced), "{:?}", string);
+        assert_eq!(automatic.size(), advanced.size());
+        assert_eq!(automatic.image_bounds(), advanced.image_bounds());
+
+        // The basic shaper is only picked when it makes no difference.
+        match automatic.shaping_mode() {
+            ShapingMode::Basic => assert_eq!(glyphs(&basic), glyphs(&advanced)),
+            _ => assert_ne!(glyphs(&basic), glyphs(&advanced)),
+        }
+    }
+}
+
+#[test]
+fn shaping_mode_automatic() {
+    let mut text = text();
+    let mut mode = |string: &str, family: FontFamily| {
+        text.new_text_layout(string.to_owned())
+            .font(family, 12.0)
+            .build()
+            .unwrap()
+            .shaping_mode()
+    };
+
+    // Simple text in a font without kerning or ligatures uses the basic shaper.
+    assert_eq!(
+        mode("Hello, world!", FontFamily::MONOSPACE),
+        ShapingMode::Basic
+    );
+
+    // Kerning and ligatures need the advanced shaper.
+    assert_eq!(
+        mode("AVATAR", FontFamily::SANS_SERIF),
+        ShapingMode::Advanced
+    );
+    assert_eq!(
+        mode("office", FontFamily::SANS_SERIF),
+        ShapingMode::Advanced
+    );
+
+    // So do combining characters, right-to-left text and complex scripts.
+    assert_eq!(
+        mode("e\u{301}", FontFamily::MONOSPACE),
+        ShapingMode::Advanced
+    );
+    assert_eq!(mode("שלום", FontFamily::MONOSPACE), ShapingMode::Advanced);
+    assert_eq!(mode("नमस्ते", FontFamily::MONOSPACE), ShapingMode::Advanced);
+}
+
+#[test]
+fn shaping_mode_automatic_long_text() {
+    let mut text = text();
+    let string = "It was the best of times, it was the worst of times, it was the age of \
+                  wisdom, it was the age of foolishness (1859).\n"
+        .repeat(40);
+
+    for family in [FontFamily::MONOSPACE, FontFamily::SANS_SERIF] {
+        let mut build = |mode| {
+            text.new_text_layout(string.clone())
+                .font(family.clone(), 12.0)
+                .max_width(300.0)
+                .range_attribute(100..200, TextAttribute::Weight(FontWeight::BOLD))
+                .shaping_mode(mode)
+                .build()
+                .unwrap()
+        };
+        let automatic = build(ShapingMode::Automatic);
+        let basic = build(ShapingMode::Basic);
+        let advanced = build(ShapingMode::Advanced);
+
+        // The shaper is picked before shaping, and gives the same result as advanced shaping.
+        assert_eq!(glyphs(&automatic), glyphs(&advanced));
+        assert_eq!(automatic.size(), advanced.size());
+        if automatic.shaping_mode() == ShapingMode::Basic {
+            assert_eq!(glyphs(&basic), glyphs(&advanced));
+        }
+    }
+
+    // Text without kerning or ligatures is shaped with the basic shaper.
+    let string = "Hello, world! ".repeat(200);
+    let layout = text
+        .new_text_layout(string)
+        .font(FontFamily::MONOSPACE, 12.0)
+        .max_width(300.0)
+        .build()
+        .unwrap();
+    assert_eq!(layout.shaping_mode(), ShapingMode::Basic);
+}
+
+#[test]
+fn range_features() {
+    let mut text = text();
+    let glyph_counts = |layout: &TextLayout| {
+        let mut counts = [0, 0];
+        for glyph in layout.layout_runs().flat_map(|run| run.glyphs.iter()) {
+            counts[usize::from(glyph.start >= 7)] += 1;
+        }
+        counts
+    };
+
+    // Ligatures can be turned off for some of the text.
+    let ligatures = text
+        .new_text_layout("office office")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    let mixed = text
+        .new_text_layout("office office")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .range_features(..7, [FontFeature::disable(*b"liga")])
+        .build()
+        .unwrap();
+    assert!(glyph_counts(&ligatures)[0] < 7);
+    assert_eq!(glyph_counts(&mixed), [7, glyph_counts(&ligatures)[1]]);
+
+    // Later features replace earlier ones.
+    let enabled = text
+        .new_text_layout("office office")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .range_features(.., [FontFeature::disable(*b"liga")])
+        .range_features(..7, [FontFeature::enable(*b"liga")])
+        .build()
+        .unwrap();
+    assert_eq!(glyph_counts(&enabled), [glyph_counts(&ligatures)[0], 6]);
+
+    // Turning off kerning changes the positions of glyphs.
+    let kerned = text
+        .new_text_layout("AVATAR")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .build()
+        .unwrap();
+    let unkerned = text
+        .new_text_layout("AVATAR")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .range_features(.., [FontFeature::disable(*b"kern")])
+        .build()
+        .unwrap();
+    assert!(unkerned.size().width > kerned.size().width);
+    assert!(unkerned.image_bounds().width() > kerned.image_bounds().width());
+
+    // Features need the advanced shaper.
+    let layout = text
+        .new_text_layout("Hello, world!")
+        .font(FontFamily::MONOSPACE, 12.0)
+        .range_features(.., [FontFeature::enable(*b"zero")])
+        .build()
+        .unwrap();
+    assert_eq!(layout.shaping_mode(), ShapingMode::Advanced);
+}
+
+#[test]
+fn letter_and_word_spacing() {
+    let mut text = text();
+    let layout = |text: &mut Text, letter: f64, word: f64| {
+        text.new_text_layout("Hello big world")
+            .font(FontFamily::SANS_SERIF, 12.0)
+            .range_word_spacing(.., word)
+            .range_letter_spacing(6..9, letter)
+            .build()
+            .unwrap()
+    };
+    let positions = |layout: &TextLayout| {
+        layout
+            .layout_runs()
+            .flat_map(|run| run.glyphs.iter())
+            .map(|glyph| (glyph.start, glyph.x))
+            .collect::<Vec<_>>()
+    };
+
+    // Spacing is in points, so 3 points adds 4 pixels.
+    let plain = layout(&mut text, 0.0, 0.0);
+    let tracked = layout(&mut text, 3.0, 0.0);
+    for ((start, plain_x), (_, tracked_x)) in positions(&plain).into_iter().zip(positions(&tracked))
+    {
+        let expected = match start {
+            0..=6 => 0.0,
+            7 => 4.0,
+            8 => 8.0,
+            _ => 12.0,
+        };
+        assert!((tracked_x - plain_x - expected).abs() < 0.01, "{}", start);
+    }
+
+    let widen = |layout: &TextLayout| layout.size().width - plain.size().width;
+    assert!((widen(&tracked) - 12.0).abs() < 0.01);
+    assert!(
+        (tracked.image_bounds().width() - plain.image_bounds().width() - 12.0).abs() < 1.0,
+        "{:?}",
+        tracked.image_bounds()
+    );
+
+    // Word spacing is added after the spaces.
+    let spaced = layout(&mut text, 0.0, 6.0);
+    assert!((widen(&spaced) - 16.0).abs() < 0.01);
+
+    // Hit testing follows the spacing.
+    let point = tracked.hit_test_text_position(9).point;
+    assert!((point.x - plain.hit_test_text_position(9).point.x - 12.0).abs() < 0.01);
+    assert_eq!(tracked.hit_test_point(point).idx, 9);
+}
+
+#[test]
+fn background_rects() {
+    let mut text = text();
+    let layout = text
+        .new_text_layout("Hello big world")
+        .font(FontFamily::SANS_SERIF, 12.0)
+        .max_width(60.0)
+        .range_background(0..9, Color::RED)
+        .range_background(4..5, Color::BLUE)
+        .build()
+        .unwrap();
+    assert_eq!(layout.line_count(), 3);
+
+    let rects = layout.background_rects();
+    let colors = rects
+        .iter()
+        .map(|rect| (rect.line, rect.color))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        colors,
+        [
+            (0, Color::RED),
+            (0, Color::BLUE),
+            (0, Color::RED),
+            (1, Color::RED)
+        ]
+    );
+
+    // Adjacent glyphs are merged into one rectangle covering the line.
+    let ranges = layout.range_rects(0..4);
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(rects[0].rect, ranges[0].rect);
+    assert_eq!(rects[1].rect.x0, rects[0].rect.x1);
+
+    let ranges = layout.range_rects(6..9);
+    assert_eq!(rects[3].rect, ranges[0].rect);
+}